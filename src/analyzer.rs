@@ -0,0 +1,264 @@
+use std::time::Duration;
+
+use cpal::{Sample, SizedSample, StreamConfig, StreamError};
+use dasp_sample::ToSample;
+use serde::{de::Error, Deserialize, Deserializer};
+
+use crate::{
+    audio_capture::{self, Downmix, LevelMetric, LevelScale, Processor, RmsProcessor},
+    bands::{self, BandProcessor},
+    beat::{self, BeatDetector},
+    pipeline::Shared,
+    protocol::{ThreadCommand, MAX_BANDS},
+    spectrum::{self, SpectrumProcessor},
+};
+
+pub const DEFAULT_SILENCE_THRESHOLD_DB: f32 = -60.0;
+pub const DEFAULT_STREAM_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BandMethod {
+    #[default]
+    Filter,
+    Fft,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(remote = "Self", default)]
+pub struct AnalysisConfig {
+    pub downmix: Downmix,
+    pub level_metric: LevelMetric,
+    pub level_scale: LevelScale,
+    #[serde(deserialize_with = "db_floor")]
+    pub level_db_floor: f32,
+    pub peak_hold_ms: u64,
+    pub method: BandMethod,
+    pub bands: usize,
+    pub min_frequency: f32,
+    pub max_frequency: f32,
+    #[serde(deserialize_with = "db_floor")]
+    pub db_floor: f32,
+    pub fft_size: usize,
+    pub hop: usize,
+    pub beat_sensitivity: f32,
+    pub beat_min_interval_ms: u64,
+    pub silence_threshold_db: f32,
+    pub stream_timeout_ms: u64,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        Self {
+            downmix: Downmix::default(),
+            level_metric: LevelMetric::default(),
+            level_scale: LevelScale::default(),
+            level_db_floor: bands::DEFAULT_DB_FLOOR,
+            peak_hold_ms: audio_capture::DEFAULT_PEAK_HOLD.as_millis() as u64,
+            method: BandMethod::default(),
+            bands: bands::DEFAULT_BAND_COUNT,
+            min_frequency: bands::DEFAULT_MIN_FREQUENCY,
+            max_frequency: bands::DEFAULT_MAX_FREQUENCY,
+            db_floor: bands::DEFAULT_DB_FLOOR,
+            fft_size: spectrum::DEFAULT_FFT_SIZE,
+            hop: spectrum::DEFAULT_HOP,
+            beat_sensitivity: beat::DEFAULT_SENSITIVITY,
+            beat_min_interval_ms: beat::DEFAULT_MIN_INTERVAL.as_millis() as u64,
+            silence_threshold_db: DEFAULT_SILENCE_THRESHOLD_DB,
+            stream_timeout_ms: DEFAULT_STREAM_TIMEOUT.as_millis() as u64,
+        }
+    }
+}
+
+// Band edges are spaced logarithmically from the minimum to the maximum frequency.
+impl<'de> Deserialize<'de> for AnalysisConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let config = Self::deserialize(deserializer)?;
+        if config.min_frequency > 0.0 && config.min_frequency < config.max_frequency {
+            Ok(config)
+        } else {
+            Err(D::Error::custom(format!(
+                "min_frequency must be above 0 Hz and below max_frequency, got {} to {} Hz",
+                config.min_frequency, config.max_frequency
+            )))
+        }
+    }
+}
+
+// Levels are scaled by dividing through the floor, so it has to be below 0 dBFS.
+fn db_floor<'de, D>(deserializer: D) -> Result<f32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let floor = f32::deserialize(deserializer)?;
+    if floor < 0.0 {
+        Ok(floor)
+    } else {
+        Err(D::Error::custom(format!(
+            "dB floor must be below 0, got {}",
+            floor
+        )))
+    }
+}
+
+pub enum BandSource {
+    Filter(BandProcessor),
+    Spectrum(SpectrumProcessor),
+}
+
+impl BandSource {
+    pub fn new(config: &AnalysisConfig) -> Self {
+        let band_count = config.bands.clamp(1, MAX_BANDS);
+        match config.method {
+            BandMethod::Filter => BandSource::Filter(
+                BandProcessor::new(band_count, config.min_frequency, config.max_frequency)
+                    .with_db_floor(config.db_floor),
+            ),
+            BandMethod::Fft => BandSource::Spectrum(
+                SpectrumProcessor::new(config.fft_size, config.hop, band_count)
+                    .with_frequency_range(config.min_frequency, config.max_frequency)
+                    .with_db_floor(config.db_floor),
+            ),
+        }
+    }
+
+    pub fn process_samples<S>(&mut self, data: &[S], channels: usize, sample_rate: u32)
+    where
+        S: Sample + ToSample<f32>,
+    {
+        match self {
+            BandSource::Filter(p) => p.process_samples(data, channels, sample_rate),
+            BandSource::Spectrum(p) => p.process_samples(data, channels, sample_rate),
+        }
+    }
+
+    pub fn get_bands_u8(&self) -> Vec<u8> {
+        match self {
+            BandSource::Filter(p) => p.get_bands_u8(),
+            BandSource::Spectrum(p) => p.get_bands_u8(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        match self {
+            BandSource::Filter(p) => p.clear(),
+            BandSource::Spectrum(p) => p.clear(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub level: (f32, f32),
+    pub channel_levels: Vec<f32>,
+    pub bands: Vec<u8>,
+    pub silence: Duration,
+}
+
+impl Snapshot {
+    pub fn level_u8(&self) -> (u8, u8) {
+        ((self.level.0 * 255f32) as u8, (self.level.1 * 255f32) as u8)
+    }
+}
+
+pub type SharedSnapshot = Shared<Snapshot>;
+
+pub struct Analyzer {
+    pub rms: RmsProcessor,
+    pub bands: BandSource,
+    pub beat: BeatDetector,
+    pub silence_threshold_db: f32,
+    pub stream_timeout: Option<Duration>,
+    silence: Duration,
+}
+
+impl Analyzer {
+    pub fn new(config: &AnalysisConfig) -> Self {
+        Self {
+            rms: RmsProcessor::with_downmix(config.downmix)
+                .with_metric(config.level_metric, config.level_scale)
+                .with_db_floor(config.level_db_floor)
+                .with_peak_hold(Duration::from_millis(config.peak_hold_ms)),
+            bands: BandSource::new(config),
+            beat: BeatDetector::new(
+                config.beat_sensitivity,
+                Duration::from_millis(config.beat_min_interval_ms),
+            ),
+            silence_threshold_db: config.silence_threshold_db,
+            stream_timeout: (config.stream_timeout_ms > 0)
+                .then(|| Duration::from_millis(config.stream_timeout_ms)),
+            silence: Duration::ZERO,
+        }
+    }
+
+    pub fn silence(&self) -> Duration {
+        self.silence
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            level: self.rms.get_level(),
+            channel_levels: self.rms.get_channel_level(),
+            bands: self.bands.get_bands_u8(),
+            silence: self.silence,
+        }
+    }
+
+    pub fn process_samples<S>(&mut self, data: &[S], config: &StreamConfig)
+    where
+        S: Sample + ToSample<f32>,
+    {
+        self.rms
+            .process_samples(data, config.channels as usize, config.sample_rate.0);
+        self.bands
+            .process_samples(data, config.channels as usize, config.sample_rate.0);
+        self.beat
+            .process_samples(data, config.channels as usize, config.sample_rate.0);
+
+        let (left, right) = self.rms.get_levels();
+        let peak = audio_capture::to_dbfs(left.peak.max(right.peak), f32::NEG_INFINITY);
+        if peak < self.silence_threshold_db {
+            let frames = data.len() / (config.channels as usize).max(1);
+            self.silence += Duration::from_secs_f64(frames as f64 / config.sample_rate.0 as f64);
+        } else {
+            self.silence = Duration::ZERO;
+        }
+    }
+}
+
+impl Default for Analyzer {
+    fn default() -> Self {
+        Self::new(&AnalysisConfig::default())
+    }
+}
+
+impl Processor for Analyzer {
+    fn process<S>(&mut self, data: &[S], config: &StreamConfig)
+    where
+        S: SizedSample + ToSample<f32>,
+    {
+        self.process_samples(data, config);
+    }
+
+    fn process_error(&mut self, err: StreamError) {
+        self.rms.process_error(err);
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.stream_timeout
+    }
+
+    fn process_timeout(&mut self, elapsed: Duration) {
+        self.rms.clear();
+        self.bands.clear();
+        self.beat.clear();
+        self.silence += elapsed;
+    }
+
+    fn take_event(&mut self) -> Option<ThreadCommand> {
+        self.beat.take_event()
+    }
+}
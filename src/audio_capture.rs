@@ -1,144 +1,894 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
 use cpal::{
-    traits::{DeviceTrait, HostTrait},
-    Device, InputCallbackInfo, Sample, SizedSample, StreamConfig, StreamError,
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    Device, Host, Sample, SampleFormat, SizedSample, StreamConfig, StreamError,
+    SupportedStreamConfigRange,
 };
 use dasp_sample::ToSample;
+use serde::{Deserialize, Serialize};
 use std::{
-    sync::{mpsc::Sender, Arc, Mutex},
-    time::Duration,
+    collections::VecDeque,
+    fmt::Display,
+    sync::mpsc::{self, Receiver, Sender},
+    time::{Duration, Instant},
 };
 
-use crate::protocol::ThreadCommand;
+use crate::{
+    pipeline::{AudioInput, Pipeline},
+    playback::{Playback, PlaybackConfig, SampleSource},
+    protocol::ThreadCommand,
+};
 
 pub trait Processor: Send + Sync {
-    fn process<S>(&mut self, data: &[S], info: &InputCallbackInfo, config: &StreamConfig)
+    fn process<S>(&mut self, data: &[S], config: &StreamConfig)
     where
         S: SizedSample + ToSample<f32>;
     fn process_error(&mut self, err: StreamError);
     fn timeout(&self) -> Option<Duration>;
+    fn process_timeout(&mut self, _elapsed: Duration) {}
+    fn take_event(&mut self) -> Option<ThreadCommand> {
+        None
+    }
 }
 
-pub fn get_output_audio_devices() -> Vec<Device> {
-    let mut result = Vec::new();
-    let available_hosts = cpal::available_hosts();
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioSource {
+    #[default]
+    Output,
+    Input,
+}
 
-    for host_id in available_hosts {
-        let host = cpal::host_from_id(host_id).unwrap();
-        let devices = host.devices().unwrap();
-        for device in devices {
-            result.push(device);
+impl Display for AudioSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioSource::Output => write!(f, "output"),
+            AudioSource::Input => write!(f, "input"),
         }
     }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    pub host: Option<String>,
+    pub device: Option<String>,
+    pub source: AudioSource,
+}
+
+pub fn find_host(name: Option<&str>) -> Result<Host> {
+    let Some(name) = name else {
+        return Ok(cpal::default_host());
+    };
+    let host_id = cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .with_context(|| format!("Audio host '{name}' is not available"))?;
+    Ok(cpal::host_from_id(host_id)?)
+}
+
+pub fn get_audio_devices(host: &Host, source: AudioSource) -> Result<Vec<Device>> {
+    let devices = match source {
+        AudioSource::Output => host.output_devices()?.collect(),
+        AudioSource::Input => host.input_devices()?.collect(),
+    };
+    Ok(devices)
+}
 
-    result
+pub fn get_audio_device_names(host: &Host, source: AudioSource) -> Result<Vec<String>> {
+    Ok(get_audio_devices(host, source)?
+        .iter()
+        .filter_map(|device| device.name().ok())
+        .collect())
+}
+
+pub fn get_default_audio_device(host: &Host, source: AudioSource) -> Option<Device> {
+    match source {
+        AudioSource::Output => host.default_output_device(),
+        AudioSource::Input => host.default_input_device(),
+    }
+}
+
+pub fn select_audio_device(config: &AudioConfig) -> Result<Device> {
+    let host = find_host(config.host.as_deref())?;
+    let Some(selector) = &config.device else {
+        return get_default_audio_device(&host, config.source).with_context(|| {
+            format!(
+                "No default {} device on {}",
+                config.source,
+                host.id().name()
+            )
+        });
+    };
+    let mut devices = get_audio_devices(&host, config.source)?;
+    // A plain number picks the device by its position in the device list.
+    let position = match selector.parse::<usize>() {
+        Ok(index) => (index < devices.len()).then_some(index),
+        Err(_) => {
+            let selector = selector.to_lowercase();
+            let names: Vec<String> = devices
+                .iter()
+                .map(|device| device.name().unwrap_or_default().to_lowercase())
+                .collect();
+            // A full name, e.g. from the device picker, must not match a longer one listed first.
+            names
+                .iter()
+                .position(|name| *name == selector)
+                .or_else(|| names.iter().position(|name| name.contains(&selector)))
+        }
+    };
+    match position {
+        Some(index) => Ok(devices.swap_remove(index)),
+        None => bail!(
+            "No {} device on {} matches '{}'",
+            config.source,
+            host.id().name(),
+            selector
+        ),
+    }
+}
+
+pub fn describe_audio_devices() -> Vec<String> {
+    let default_host = cpal::default_host().id();
+    let mut lines = Vec::new();
+    for host_id in cpal::available_hosts() {
+        let default = if host_id == default_host {
+            " (default)"
+        } else {
+            ""
+        };
+        lines.push(format!("Host {}{}", host_id.name(), default));
+        let Ok(host) = cpal::host_from_id(host_id) else {
+            lines.push("  unavailable".to_owned());
+            continue;
+        };
+        for source in [AudioSource::Output, AudioSource::Input] {
+            lines.push(format!("  {} devices:", source));
+            let default_name = get_default_audio_device(&host, source).and_then(|d| d.name().ok());
+            let devices = get_audio_devices(&host, source).unwrap_or_default();
+            for (index, device) in devices.iter().enumerate() {
+                let name = device.name().unwrap_or_else(|_| "<unknown>".to_owned());
+                let default = if Some(&name) == default_name.as_ref() {
+                    " (default)"
+                } else {
+                    ""
+                };
+                lines.push(format!("    [{}] {}{}", index, name, default));
+                lines.extend(describe_configs(device, source));
+            }
+        }
+    }
+    lines
 }
 
-pub fn get_default_audio_output_device() -> Option<Device> {
-    let default_host = cpal::default_host();
-    println!(
-        "{}",
-        default_host
-            .default_output_device()
-            .unwrap()
-            .name()
-            .unwrap()
-    );
-    default_host.default_output_device()
+fn describe_configs(device: &Device, source: AudioSource) -> Vec<String> {
+    let configs: Vec<SupportedStreamConfigRange> = match source {
+        AudioSource::Output => device
+            .supported_output_configs()
+            .map(|c| c.collect())
+            .unwrap_or_default(),
+        AudioSource::Input => device
+            .supported_input_configs()
+            .map(|c| c.collect())
+            .unwrap_or_default(),
+    };
+    configs
+        .iter()
+        .map(|config| {
+            format!(
+                "        {} ch, {}-{} Hz, {}",
+                config.channels(),
+                config.min_sample_rate().0,
+                config.max_sample_rate().0,
+                config.sample_format()
+            )
+        })
+        .collect()
 }
 
-pub fn capture_device_ouput<P>(
+pub fn capture_device_ouput<T>(
     device: &Device,
-    processor: Arc<Mutex<P>>,
-    tx: Sender<ThreadCommand>,
+    source: AudioSource,
+    downmix: &Downmix,
+    pipeline: &Pipeline<T>,
+    errors: Sender<StreamError>,
 ) -> Result<cpal::Stream>
 where
-    P: Processor + 'static,
+    T: Send + Sync + 'static,
 {
-    let supported_config = device.default_input_config()?;
+    // Output devices are captured in loopback mode with their playback format.
+    let supported_config = match source {
+        AudioSource::Output => device.default_output_config()?,
+        AudioSource::Input => device.default_input_config()?,
+    };
     let config = supported_config.config();
-    let move_config = config.clone();
-    let move_processor = processor.clone();
+    downmix.check(config.channels as usize)?;
+    let input = pipeline.input(&config);
     let stream = match supported_config.sample_format() {
-        cpal::SampleFormat::I8 => device.build_input_stream(
-            &config,
-            move |data, info| {
-                {
-                    let mut p = processor.lock().unwrap();
-                    p.process::<i8>(data, info, &move_config)
-                }
-                tx.send(ThreadCommand::ProcessorComplete).unwrap()
-            },
-            move |err| move_processor.lock().unwrap().process_error(err),
-            None,
-        )?,
-        cpal::SampleFormat::I16 => device.build_input_stream(
-            &config,
-            move |data, info| {
-                {
-                    let mut p = processor.lock().unwrap();
-                    p.process::<i16>(data, info, &move_config)
-                }
-                tx.send(ThreadCommand::ProcessorComplete).unwrap()
-            },
-            move |err| move_processor.lock().unwrap().process_error(err),
-            None,
-        )?,
-        cpal::SampleFormat::I32 => device.build_input_stream(
-            &config,
-            move |data, info| {
-                {
-                    let mut p = processor.lock().unwrap();
-                    p.process::<i32>(data, info, &move_config)
-                }
-                tx.send(ThreadCommand::ProcessorComplete).unwrap()
-            },
-            move |err| move_processor.lock().unwrap().process_error(err),
-            None,
-        )?,
-        cpal::SampleFormat::F32 => device.build_input_stream(
-            &config,
-            move |data, info| {
-                {
-                    let mut p = processor.lock().unwrap();
-                    p.process::<f32>(data, info, &move_config)
-                }
-                let send = tx.send(ThreadCommand::ProcessorComplete);
-                match send {
-                    Ok(_) => (),
-                    Err(err) => println!("Cannot send ProcessorComplete command. Reason: {err}"),
+        SampleFormat::I8 => build_input_stream::<i8>(device, &config, input, errors),
+        SampleFormat::I16 => build_input_stream::<i16>(device, &config, input, errors),
+        SampleFormat::I32 => build_input_stream::<i32>(device, &config, input, errors),
+        SampleFormat::I64 => build_input_stream::<i64>(device, &config, input, errors),
+        SampleFormat::U8 => build_input_stream::<u8>(device, &config, input, errors),
+        SampleFormat::U16 => build_input_stream::<u16>(device, &config, input, errors),
+        SampleFormat::U32 => build_input_stream::<u32>(device, &config, input, errors),
+        SampleFormat::U64 => build_input_stream::<u64>(device, &config, input, errors),
+        SampleFormat::F32 => build_input_stream::<f32>(device, &config, input, errors),
+        SampleFormat::F64 => build_input_stream::<f64>(device, &config, input, errors),
+        sample_format => Err(anyhow::Error::msg(format!(
+            "Unsupported sample format '{sample_format}'"
+        ))),
+    }?;
+    stream.play()?;
+    Ok(stream)
+}
+
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+// A burst of backend errors this large within the window means the stream is broken.
+const BACKEND_ERROR_LIMIT: usize = 5;
+const BACKEND_ERROR_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamFault {
+    DeviceLost,
+    Transient,
+}
+
+impl StreamFault {
+    pub fn classify(err: &StreamError) -> Self {
+        match err {
+            StreamError::DeviceNotAvailable => StreamFault::DeviceLost,
+            StreamError::BackendSpecific { .. } => StreamFault::Transient,
+        }
+    }
+}
+
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt += 1;
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(DEFAULT_RETRY_DELAY, MAX_RETRY_DELAY)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CaptureStatus {
+    Stopped,
+    Running,
+    Degraded(String),
+    Recovering {
+        reason: String,
+        attempt: u32,
+        retry_at: Instant,
+    },
+}
+
+impl Display for CaptureStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptureStatus::Stopped => write!(f, "Stopped"),
+            CaptureStatus::Running => write!(f, "Running"),
+            CaptureStatus::Degraded(reason) => write!(f, "Running with errors: {}", reason),
+            CaptureStatus::Recovering {
+                reason,
+                attempt,
+                retry_at,
+            } => write!(
+                f,
+                "{}, retry {} in {:.1}s",
+                reason,
+                attempt,
+                retry_at
+                    .saturating_duration_since(Instant::now())
+                    .as_secs_f32()
+            ),
+        }
+    }
+}
+
+// Opens the configured device, returns its name and the stream that keeps it running.
+pub type OpenStream<T, S> = Box<
+    dyn FnMut(&AudioConfig, &Downmix, &Pipeline<T>, Sender<StreamError>) -> Result<(String, S)>,
+>;
+
+pub struct AudioCapture<T, S = cpal::Stream> {
+    pipeline: Pipeline<T>,
+    config: AudioConfig,
+    downmix: Downmix,
+    open_stream: OpenStream<T, S>,
+    device_name: Option<String>,
+    stream: Option<S>,
+    playback: Option<Playback>,
+    status: CaptureStatus,
+    backoff: Backoff,
+    errors_tx: Sender<StreamError>,
+    errors: Receiver<StreamError>,
+    backend_errors: VecDeque<Instant>,
+}
+
+impl<T> AudioCapture<T>
+where
+    T: Send + Sync + 'static,
+{
+    pub fn new(pipeline: Pipeline<T>, config: AudioConfig) -> Self {
+        Self::with_open_stream(
+            pipeline,
+            config,
+            Box::new(|config, downmix, pipeline, errors| {
+                let device = select_audio_device(config)?;
+                let stream =
+                    capture_device_ouput(&device, config.source, downmix, pipeline, errors)?;
+                Ok((device.name()?, stream))
+            }),
+        )
+    }
+}
+
+impl<T, S> AudioCapture<T, S>
+where
+    T: Send + Sync + 'static,
+{
+    pub fn with_open_stream(
+        pipeline: Pipeline<T>,
+        config: AudioConfig,
+        open_stream: OpenStream<T, S>,
+    ) -> Self {
+        let (errors_tx, errors) = mpsc::channel();
+        Self {
+            pipeline,
+            config,
+            downmix: Downmix::default(),
+            open_stream,
+            device_name: None,
+            stream: None,
+            playback: None,
+            status: CaptureStatus::Stopped,
+            backoff: Backoff::default(),
+            errors_tx,
+            errors,
+            backend_errors: VecDeque::new(),
+        }
+    }
+
+    // Channels the downmix refers to must exist on every stream that is opened.
+    pub fn with_downmix(mut self, downmix: Downmix) -> Self {
+        self.downmix = downmix;
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn pipeline(&self) -> &Pipeline<T> {
+        &self.pipeline
+    }
+
+    pub fn config(&self) -> &AudioConfig {
+        &self.config
+    }
+
+    pub fn device_name(&self) -> Option<&str> {
+        self.device_name.as_deref()
+    }
+
+    pub fn status(&self) -> &CaptureStatus {
+        &self.status
+    }
+
+    pub fn playback(&self) -> Option<&Playback> {
+        self.playback.as_ref()
+    }
+
+    // Feeds the pipeline from a file or generator instead of a device until the next switch.
+    pub fn play(
+        &mut self,
+        name: String,
+        source: Box<dyn SampleSource>,
+        config: &PlaybackConfig,
+    ) -> Result<()> {
+        self.downmix.check(source.config().channels as usize)?;
+        self.stream = None;
+        self.playback = None;
+        self.device_name = Some(name.clone());
+        self.playback = Some(Playback::spawn(name, source, config, &self.pipeline));
+        self.status = CaptureStatus::Running;
+        Ok(())
+    }
+
+    pub fn switch(&mut self, config: AudioConfig) -> Result<()> {
+        self.config = config;
+        self.backoff.reset();
+        self.restart()
+    }
+
+    pub fn stop(&mut self) {
+        self.stream = None;
+        self.playback = None;
+        self.status = CaptureStatus::Stopped;
+    }
+
+    fn restart(&mut self) -> Result<()> {
+        self.stream = None;
+        self.playback = None;
+        self.device_name = None;
+        self.backend_errors.clear();
+        // Errors of the old stream must not tear down the new one.
+        self.errors.try_iter().for_each(drop);
+        match self.open() {
+            Ok(()) => {
+                self.status = CaptureStatus::Running;
+                Ok(())
+            }
+            Err(err) => {
+                self.schedule_retry(err.to_string());
+                Err(err)
+            }
+        }
+    }
+
+    fn open(&mut self) -> Result<()> {
+        let (name, stream) = (self.open_stream)(
+            &self.config,
+            &self.downmix,
+            &self.pipeline,
+            self.errors_tx.clone(),
+        )?;
+        self.stream = Some(stream);
+        self.device_name = Some(name);
+        Ok(())
+    }
+
+    fn schedule_retry(&mut self, reason: String) {
+        self.stream = None;
+        let delay = self.backoff.next_delay();
+        self.status = CaptureStatus::Recovering {
+            reason,
+            attempt: self.backoff.attempt(),
+            retry_at: Instant::now() + delay,
+        };
+    }
+
+    fn expire_backend_errors(&mut self, now: Instant) {
+        while self
+            .backend_errors
+            .front()
+            .is_some_and(|at| now - *at > BACKEND_ERROR_WINDOW)
+        {
+            self.backend_errors.pop_front();
+        }
+    }
+
+    // Handles stream errors and pending retries, call it regularly from the UI loop.
+    pub fn poll(&mut self) {
+        let now = Instant::now();
+        // A device loss is often reported more than once, it still counts as one retry.
+        let mut lost = None;
+        for err in self.errors.try_iter().collect::<Vec<_>>() {
+            match StreamFault::classify(&err) {
+                StreamFault::DeviceLost => lost = Some(err.to_string()),
+                StreamFault::Transient => {
+                    self.backend_errors.push_back(now);
+                    self.expire_backend_errors(now);
+                    if self.backend_errors.len() >= BACKEND_ERROR_LIMIT {
+                        lost = Some(err.to_string());
+                    } else if self.stream.is_some() {
+                        self.status = CaptureStatus::Degraded(err.to_string());
+                    }
                 }
-            },
-            move |err| move_processor.lock().unwrap().process_error(err),
-            None,
-        )?,
-        sample_format => {
-            return Err(anyhow::Error::msg(format!(
-                "Unsupported sample format '{sample_format}'"
-            )))
+            }
+        }
+        self.expire_backend_errors(now);
+        if let Some(reason) = lost {
+            self.schedule_retry(reason);
+        } else if self.backend_errors.is_empty()
+            && self.stream.is_some()
+            && matches!(self.status, CaptureStatus::Degraded(_))
+        {
+            self.status = CaptureStatus::Running;
+        }
+        if let Some(result) = self.playback.as_mut().and_then(Playback::finished) {
+            self.status = match result {
+                Ok(()) => CaptureStatus::Stopped,
+                Err(err) => CaptureStatus::Degraded(err.to_string()),
+            };
         }
+        if let CaptureStatus::Recovering { retry_at, .. } = self.status {
+            if now >= retry_at && self.restart().is_ok() {
+                self.backoff.reset();
+            }
+        }
+    }
+
+    // Returns true when the capture was moved to a new system default device.
+    pub fn follow_default(&mut self) -> Result<bool> {
+        if self.config.device.is_some() || self.stream.is_none() {
+            return Ok(false);
+        }
+        let host = find_host(self.config.host.as_deref())?;
+        let default_name =
+            get_default_audio_device(&host, self.config.source).and_then(|d| d.name().ok());
+        if default_name.is_none() || default_name == self.device_name {
+            return Ok(false);
+        }
+        self.switch(self.config.clone())?;
+        Ok(true)
+    }
+}
+
+fn build_input_stream<S>(
+    device: &Device,
+    config: &StreamConfig,
+    mut input: AudioInput,
+    errors: Sender<StreamError>,
+) -> Result<cpal::Stream>
+where
+    S: SizedSample + ToSample<f32>,
+{
+    let mut forward = input.error_handler();
+    let on_error = move |err: StreamError| {
+        forward(copy_stream_error(&err));
+        let _ = errors.send(err);
     };
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[S], _| input.push(data),
+        on_error,
+        None,
+    )?;
     Ok(stream)
 }
 
+fn copy_stream_error(err: &StreamError) -> StreamError {
+    match err {
+        StreamError::DeviceNotAvailable => StreamError::DeviceNotAvailable,
+        StreamError::BackendSpecific { err } => StreamError::BackendSpecific { err: err.clone() },
+    }
+}
+
+const SURROUND_NAMES: [&str; 8] = ["FL", "FR", "FC", "LFE", "BL", "BR", "SL", "SR"];
+const SURROUND_MIX: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Downmix {
+    #[default]
+    Auto,
+    Front,
+    Mono,
+    Map {
+        left: usize,
+        right: usize,
+    },
+}
+
+impl Downmix {
+    pub fn weights(&self, channels: usize) -> Vec<(f32, f32)> {
+        let layout = surround_layout(channels);
+        (0..channels)
+            .map(|channel| match (self, channels) {
+                (Downmix::Mono, _) => (1f32 / channels as f32, 1f32 / channels as f32),
+                (Downmix::Map { left, right }, _) => (
+                    (channel == *left) as u8 as f32,
+                    (channel == *right) as u8 as f32,
+                ),
+                (_, 1) => (1f32, 1f32),
+                (Downmix::Front, _) | (Downmix::Auto, 2) => match channel {
+                    0 => (1f32, 0f32),
+                    1 => (0f32, 1f32),
+                    _ => (0f32, 0f32),
+                },
+                // Fold surround layouts to stereo, LFE is dropped.
+                (Downmix::Auto, _) => match layout.get(channel) {
+                    Some(&"FL") => (1f32, 0f32),
+                    Some(&"FR") => (0f32, 1f32),
+                    Some(&"FC") => (SURROUND_MIX, SURROUND_MIX),
+                    Some(&"BL") | Some(&"SL") => (SURROUND_MIX, 0f32),
+                    Some(&"BR") | Some(&"SR") => (0f32, SURROUND_MIX),
+                    _ => (0f32, 0f32),
+                },
+            })
+            .collect()
+    }
+
+    pub fn check(&self, channels: usize) -> Result<()> {
+        if let Downmix::Map { left, right } = self {
+            let channel = (*left).max(*right);
+            if channel >= channels {
+                bail!(
+                    "Downmix maps channel {}, but the stream only has {} channels",
+                    channel,
+                    channels
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// 2.1 and quad have no center channel, so they do not follow the 5.1/7.1 order.
+fn surround_layout(channels: usize) -> &'static [&'static str] {
+    match channels {
+        3 => &["FL", "FR", "LFE"],
+        4 => &["FL", "FR", "BL", "BR"],
+        _ => &SURROUND_NAMES,
+    }
+}
+
+pub fn channel_name(channel: usize, channels: usize) -> String {
+    if channels == 1 {
+        return "M".to_owned();
+    }
+    surround_layout(channels)
+        .get(channel)
+        .map_or_else(|| format!("CH{}", channel + 1), |name| name.to_string())
+}
+
+pub const DEFAULT_PEAK_HOLD: Duration = Duration::from_millis(1000);
+// How fast a held peak falls back once the hold time is over.
+const PEAK_RELEASE_DB_PER_SECOND: f32 = 20.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LevelMetric {
+    #[default]
+    Rms,
+    Peak,
+    PeakHold,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LevelScale {
+    #[default]
+    Linear,
+    Db,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Levels {
+    pub rms: f32,
+    pub peak: f32,
+    pub peak_hold: f32,
+    hold_remaining: f32,
+}
+
+impl Levels {
+    pub fn get(&self, metric: LevelMetric) -> f32 {
+        match metric {
+            LevelMetric::Rms => self.rms,
+            LevelMetric::Peak => self.peak,
+            LevelMetric::PeakHold => self.peak_hold,
+        }
+    }
+
+    fn update(&mut self, rms: f32, peak: f32, elapsed: f32, hold: f32) {
+        self.rms = rms;
+        self.peak = peak;
+        if peak >= self.peak_hold {
+            self.peak_hold = peak;
+            self.hold_remaining = hold;
+            return;
+        }
+        let release = (elapsed - self.hold_remaining).max(0f32);
+        self.hold_remaining = (self.hold_remaining - elapsed).max(0f32);
+        let fall = 10f32.powf(-PEAK_RELEASE_DB_PER_SECOND * release / 20.0);
+        self.peak_hold = (self.peak_hold * fall).max(peak);
+    }
+}
+
+pub fn to_dbfs(level: f32, db_floor: f32) -> f32 {
+    (20.0 * level.max(f32::MIN_POSITIVE).log10()).max(db_floor)
+}
+
 pub struct RmsProcessor {
-    rms: (f32, f32),
+    downmix: Downmix,
+    metric: LevelMetric,
+    scale: LevelScale,
+    db_floor: f32,
+    peak_hold: Duration,
+    levels: (Levels, Levels),
+    weights: Vec<(f32, f32)>,
+    channel_sums: Vec<f32>,
+    channel_peaks: Vec<f32>,
+    channel_levels: Vec<Levels>,
 }
 
 impl RmsProcessor {
     pub fn new() -> Self {
-        Self { rms: (0f32, 0f32) }
+        Self::with_downmix(Downmix::default())
+    }
+
+    pub fn with_downmix(downmix: Downmix) -> Self {
+        Self {
+            downmix,
+            metric: LevelMetric::default(),
+            scale: LevelScale::default(),
+            db_floor: crate::bands::DEFAULT_DB_FLOOR,
+            peak_hold: DEFAULT_PEAK_HOLD,
+            levels: (Levels::default(), Levels::default()),
+            weights: Vec::new(),
+            channel_sums: Vec::new(),
+            channel_peaks: Vec::new(),
+            channel_levels: Vec::new(),
+        }
+    }
+
+    pub fn with_metric(mut self, metric: LevelMetric, scale: LevelScale) -> Self {
+        self.metric = metric;
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_db_floor(mut self, db_floor: f32) -> Self {
+        self.db_floor = db_floor;
+        self
+    }
+
+    pub fn with_peak_hold(mut self, peak_hold: Duration) -> Self {
+        self.peak_hold = peak_hold;
+        self
     }
 
     pub fn get_rms<T>(&self) -> (T, T)
     where
         T: cpal::FromSample<f32>,
     {
-        (self.rms.0.to_sample::<T>(), self.rms.0.to_sample::<T>())
+        (
+            self.levels.0.rms.to_sample::<T>(),
+            self.levels.1.rms.to_sample::<T>(),
+        )
     }
 
     pub fn get_rms_u8(&self) -> (u8, u8) {
-        ((self.rms.0 * 255f32) as u8, (self.rms.1 * 255f32) as u8)
+        (
+            (self.levels.0.rms * 255f32) as u8,
+            (self.levels.1.rms * 255f32) as u8,
+        )
+    }
+
+    pub fn get_levels(&self) -> (Levels, Levels) {
+        self.levels
+    }
+
+    pub fn get_dbfs(&self, metric: LevelMetric) -> (f32, f32) {
+        (
+            to_dbfs(self.levels.0.get(metric), self.db_floor),
+            to_dbfs(self.levels.1.get(metric), self.db_floor),
+        )
+    }
+
+    fn normalize(&self, level: f32) -> f32 {
+        match self.scale {
+            LevelScale::Linear => level.clamp(0f32, 1f32),
+            // Folded surround channels can go above full scale.
+            LevelScale::Db => {
+                (1f32 - to_dbfs(level, self.db_floor) / self.db_floor).clamp(0f32, 1f32)
+            }
+        }
+    }
+
+    pub fn get_level(&self) -> (f32, f32) {
+        (
+            self.normalize(self.levels.0.get(self.metric)),
+            self.normalize(self.levels.1.get(self.metric)),
+        )
+    }
+
+    pub fn get_level_u8(&self) -> (u8, u8) {
+        let level = self.get_level();
+        ((level.0 * 255f32) as u8, (level.1 * 255f32) as u8)
+    }
+
+    pub fn clear(&mut self) {
+        self.levels = (Levels::default(), Levels::default());
+        self.channel_levels.fill(Levels::default());
+    }
+
+    pub fn get_channel_levels(&self) -> &[Levels] {
+        &self.channel_levels
+    }
+
+    pub fn get_channel_rms(&self) -> Vec<f32> {
+        self.channel_levels.iter().map(|l| l.rms).collect()
+    }
+
+    pub fn get_channel_level(&self) -> Vec<f32> {
+        self.channel_levels
+            .iter()
+            .map(|l| self.normalize(l.get(self.metric)))
+            .collect()
+    }
+
+    pub fn process_samples<S>(&mut self, data: &[S], channels: usize, sample_rate: u32)
+    where
+        S: Sample + ToSample<f32>,
+    {
+        if channels == 0 || data.len() < channels {
+            return;
+        }
+        if self.weights.len() != channels {
+            self.weights = self.downmix.weights(channels);
+            self.channel_sums = vec![0f32; channels];
+            self.channel_peaks = vec![0f32; channels];
+            self.channel_levels = vec![Levels::default(); channels];
+        }
+        self.channel_sums.fill(0f32);
+        self.channel_peaks.fill(0f32);
+
+        let mut sum = (0f32, 0f32);
+        let mut peak = (0f32, 0f32);
+        for frame in data.chunks_exact(channels) {
+            let mut mixed = (0f32, 0f32);
+            for (((sample, weight), channel_sum), channel_peak) in frame
+                .iter()
+                .zip(&self.weights)
+                .zip(self.channel_sums.iter_mut())
+                .zip(self.channel_peaks.iter_mut())
+            {
+                let sample = sample.to_sample::<f32>();
+                *channel_sum += sample * sample;
+                *channel_peak = channel_peak.max(sample.abs());
+                mixed.0 += sample * weight.0;
+                mixed.1 += sample * weight.1;
+            }
+            sum.0 += mixed.0 * mixed.0;
+            sum.1 += mixed.1 * mixed.1;
+            peak.0 = peak.0.max(mixed.0.abs());
+            peak.1 = peak.1.max(mixed.1.abs());
+        }
+
+        let frames = (data.len() / channels) as f32;
+        let elapsed = frames / sample_rate.max(1) as f32;
+        let hold = self.peak_hold.as_secs_f32();
+        self.levels
+            .0
+            .update((sum.0 / frames).sqrt(), peak.0, elapsed, hold);
+        self.levels
+            .1
+            .update((sum.1 / frames).sqrt(), peak.1, elapsed, hold);
+        for ((levels, channel_sum), channel_peak) in self
+            .channel_levels
+            .iter_mut()
+            .zip(&self.channel_sums)
+            .zip(&self.channel_peaks)
+        {
+            levels.update((channel_sum / frames).sqrt(), *channel_peak, elapsed, hold);
+        }
     }
 }
 
@@ -149,25 +899,14 @@ impl Default for RmsProcessor {
 }
 
 impl Processor for RmsProcessor {
-    fn process<S>(&mut self, data: &[S], _info: &InputCallbackInfo, _config: &StreamConfig)
+    fn process<S>(&mut self, data: &[S], config: &StreamConfig)
     where
         S: SizedSample + ToSample<f32>,
     {
-        let mut sum = (0f32, 0f32);
-        for frame in data.chunks_exact(2) {
-            let samples = (frame[0].to_sample::<f32>(), frame[1].to_sample::<f32>());
-            sum.0 += samples.0 * samples.0;
-            sum.1 += samples.1 * samples.1;
-        }
-
-        let len_2 = (data.len() / 2) as f32;
-        self.rms.0 = (sum.0 / len_2).sqrt();
-        self.rms.1 = (sum.1 / len_2).sqrt();
+        self.process_samples(data, config.channels as usize, config.sample_rate.0);
     }
 
-    fn process_error(&mut self, err: StreamError) {
-        println!("{}", err);
-    }
+    fn process_error(&mut self, _err: StreamError) {}
 
     fn timeout(&self) -> Option<Duration> {
         None
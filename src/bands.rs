@@ -0,0 +1,170 @@
+use std::{f32::consts::PI, time::Duration};
+
+use cpal::{Sample, SizedSample, StreamConfig, StreamError};
+use dasp_sample::ToSample;
+
+use crate::audio_capture::Processor;
+
+pub const DEFAULT_BAND_COUNT: usize = 16;
+pub const DEFAULT_MIN_FREQUENCY: f32 = 40.0;
+pub const DEFAULT_MAX_FREQUENCY: f32 = 16000.0;
+pub const DEFAULT_DB_FLOOR: f32 = -60.0;
+
+#[derive(Clone, Copy, Default)]
+struct BandPass {
+    b0: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl BandPass {
+    fn new(center: f32, q: f32, sample_rate: f32) -> Self {
+        let w0 = 2.0 * PI * center / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: alpha / a0,
+            b2: -alpha / a0,
+            a1: -2.0 * w0.cos() / a0,
+            a2: (1.0 - alpha) / a0,
+            ..Self::default()
+        }
+    }
+
+    fn next(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+pub struct BandProcessor {
+    band_count: usize,
+    min_frequency: f32,
+    max_frequency: f32,
+    db_floor: f32,
+    sample_rate: u32,
+    filters: Vec<BandPass>,
+    bands: Vec<f32>,
+}
+
+impl BandProcessor {
+    pub fn new(band_count: usize, min_frequency: f32, max_frequency: f32) -> Self {
+        Self {
+            band_count,
+            min_frequency,
+            max_frequency,
+            db_floor: DEFAULT_DB_FLOOR,
+            sample_rate: 0,
+            filters: Vec::new(),
+            bands: vec![0f32; band_count],
+        }
+    }
+
+    pub fn with_db_floor(mut self, db_floor: f32) -> Self {
+        self.db_floor = db_floor;
+        self
+    }
+
+    pub fn center_frequencies(&self, sample_rate: u32) -> Vec<f32> {
+        let max_frequency = self.max_frequency.min(sample_rate as f32 * 0.45);
+        let ratio = max_frequency / self.min_frequency;
+        (0..self.band_count)
+            .map(|band| {
+                let position = (band as f32 + 0.5) / self.band_count as f32;
+                self.min_frequency * ratio.powf(position)
+            })
+            .collect()
+    }
+
+    fn rebuild_filters(&mut self, sample_rate: u32) {
+        let centers = self.center_frequencies(sample_rate);
+        let max_frequency = self.max_frequency.min(sample_rate as f32 * 0.45);
+        let octaves = (max_frequency / self.min_frequency).log2() / self.band_count as f32;
+        let bandwidth = 2f32.powf(octaves);
+        let q = bandwidth.sqrt() / (bandwidth - 1.0);
+        self.filters = centers
+            .iter()
+            .map(|center| BandPass::new(*center, q, sample_rate as f32))
+            .collect();
+        self.sample_rate = sample_rate;
+    }
+
+    pub fn process_samples<S>(&mut self, data: &[S], channels: usize, sample_rate: u32)
+    where
+        S: Sample + ToSample<f32>,
+    {
+        if channels == 0 || data.len() < channels {
+            return;
+        }
+        if self.sample_rate != sample_rate {
+            self.rebuild_filters(sample_rate);
+        }
+        let mut sums = vec![0f32; self.band_count];
+        for frame in data.chunks_exact(channels) {
+            let mono = frame.iter().map(|s| s.to_sample::<f32>()).sum::<f32>() / channels as f32;
+            for (filter, sum) in self.filters.iter_mut().zip(sums.iter_mut()) {
+                let y = filter.next(mono);
+                *sum += y * y;
+            }
+        }
+        let frames = (data.len() / channels) as f32;
+        for (band, sum) in self.bands.iter_mut().zip(sums) {
+            *band = (sum / frames).sqrt();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.bands.fill(0f32);
+    }
+
+    pub fn get_bands(&self) -> &[f32] {
+        &self.bands
+    }
+
+    pub fn get_bands_u8(&self) -> Vec<u8> {
+        self.bands
+            .iter()
+            .map(|band| quantize_db(*band, self.db_floor))
+            .collect()
+    }
+}
+
+impl Default for BandProcessor {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_BAND_COUNT,
+            DEFAULT_MIN_FREQUENCY,
+            DEFAULT_MAX_FREQUENCY,
+        )
+    }
+}
+
+impl Processor for BandProcessor {
+    fn process<S>(&mut self, data: &[S], config: &StreamConfig)
+    where
+        S: SizedSample + ToSample<f32>,
+    {
+        self.process_samples(data, config.channels as usize, config.sample_rate.0);
+    }
+
+    fn process_error(&mut self, _err: StreamError) {}
+
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+}
+
+pub fn quantize_db(level: f32, db_floor: f32) -> u8 {
+    let db = 20.0 * level.max(f32::MIN_POSITIVE).log10();
+    let normalized = ((db - db_floor) / -db_floor).clamp(0.0, 1.0);
+    (normalized * 255.0).round() as u8
+}
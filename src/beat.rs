@@ -0,0 +1,185 @@
+use std::{collections::VecDeque, time::Duration};
+
+use cpal::{Sample, SizedSample, StreamConfig, StreamError};
+use dasp_sample::ToSample;
+
+use crate::{audio_capture::Processor, protocol::ThreadCommand};
+
+pub const DEFAULT_SENSITIVITY: f32 = 1.5;
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_millis(300);
+const FRAME_DURATION: f32 = 0.01;
+const HISTORY_DURATION: f32 = 1.0;
+// Without enough history any onset stands out, e.g. the first one after a gap.
+const MIN_HISTORY_DURATION: f32 = 0.2;
+const INTERVAL_HISTORY: usize = 8;
+const MIN_FLUX: f32 = 1e-6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Beat {
+    pub strength: f32,
+    pub bpm: f32,
+}
+
+pub struct BeatDetector {
+    pub sensitivity: f32,
+    pub min_interval: Duration,
+    sample_rate: u32,
+    frame_size: usize,
+    frame_energy: f32,
+    frame_samples: usize,
+    previous_energy: f32,
+    history: VecDeque<f32>,
+    history_len: usize,
+    min_history_len: usize,
+    position: u64,
+    last_beat: Option<u64>,
+    intervals: VecDeque<f32>,
+    bpm: f32,
+    pending: Option<Beat>,
+}
+
+impl BeatDetector {
+    pub fn new(sensitivity: f32, min_interval: Duration) -> Self {
+        Self {
+            sensitivity,
+            min_interval,
+            sample_rate: 0,
+            frame_size: 0,
+            frame_energy: 0f32,
+            frame_samples: 0,
+            previous_energy: 0f32,
+            history: VecDeque::new(),
+            history_len: 0,
+            min_history_len: 0,
+            position: 0,
+            last_beat: None,
+            intervals: VecDeque::with_capacity(INTERVAL_HISTORY),
+            bpm: 0f32,
+            pending: None,
+        }
+    }
+
+    fn reset(&mut self, sample_rate: u32) {
+        *self = Self::new(self.sensitivity, self.min_interval);
+        self.sample_rate = sample_rate;
+        self.frame_size = ((sample_rate as f32 * FRAME_DURATION) as usize).max(1);
+        self.history_len = (HISTORY_DURATION / FRAME_DURATION) as usize;
+        self.min_history_len = (MIN_HISTORY_DURATION / FRAME_DURATION) as usize;
+    }
+
+    // Forgets the flux history, e.g. after the stream went quiet for a while.
+    pub fn clear(&mut self) {
+        self.frame_energy = 0f32;
+        self.frame_samples = 0;
+        self.previous_energy = 0f32;
+        self.history.clear();
+        self.pending = None;
+    }
+
+    pub fn bpm(&self) -> f32 {
+        self.bpm
+    }
+
+    pub fn take_beat(&mut self) -> Option<Beat> {
+        self.pending.take()
+    }
+
+    pub fn process_samples<S>(&mut self, data: &[S], channels: usize, sample_rate: u32)
+    where
+        S: Sample + ToSample<f32>,
+    {
+        if channels == 0 || sample_rate == 0 {
+            return;
+        }
+        if self.sample_rate != sample_rate {
+            self.reset(sample_rate);
+        }
+        for frame in data.chunks_exact(channels) {
+            let mono = frame.iter().map(|s| s.to_sample::<f32>()).sum::<f32>() / channels as f32;
+            self.frame_energy += mono * mono;
+            self.frame_samples += 1;
+            self.position += 1;
+            if self.frame_samples == self.frame_size {
+                let energy = self.frame_energy / self.frame_size as f32;
+                self.process_frame(energy);
+                self.frame_energy = 0f32;
+                self.frame_samples = 0;
+            }
+        }
+    }
+
+    fn process_frame(&mut self, energy: f32) {
+        let flux = (energy - self.previous_energy).max(0f32);
+        self.previous_energy = energy;
+
+        let warmed_up = self.history.len() >= self.min_history_len;
+        let count = self.history.len().max(1) as f32;
+        let mean = self.history.iter().sum::<f32>() / count;
+        let variance = self.history.iter().map(|f| (f - mean).powi(2)).sum::<f32>() / count;
+        let threshold = mean + self.sensitivity * variance.sqrt();
+
+        if self.history.len() == self.history_len {
+            self.history.pop_front();
+        }
+        self.history.push_back(flux);
+
+        let min_interval = (self.min_interval.as_secs_f32() * self.sample_rate as f32) as u64;
+        let ready = self
+            .last_beat
+            .is_none_or(|last| self.position - last >= min_interval);
+        if warmed_up && flux > threshold && flux > MIN_FLUX && ready {
+            self.register_beat(flux, threshold);
+        }
+    }
+
+    fn register_beat(&mut self, flux: f32, threshold: f32) {
+        if let Some(last) = self.last_beat {
+            let interval = (self.position - last) as f32 / self.sample_rate as f32;
+            // Only plausible tempos between 30 and 240 BPM feed the estimate.
+            if (0.25..=2.0).contains(&interval) {
+                if self.intervals.len() == INTERVAL_HISTORY {
+                    self.intervals.pop_front();
+                }
+                self.intervals.push_back(interval);
+                let mut sorted: Vec<f32> = self.intervals.iter().copied().collect();
+                sorted.sort_by(f32::total_cmp);
+                self.bpm = 60f32 / sorted[sorted.len() / 2];
+            }
+        }
+        self.last_beat = Some(self.position);
+        let strength = if threshold > 0f32 {
+            ((flux / threshold - 1f32) / 4f32).clamp(0f32, 1f32)
+        } else {
+            1f32
+        };
+        self.pending = Some(Beat {
+            strength,
+            bpm: self.bpm,
+        });
+    }
+}
+
+impl Default for BeatDetector {
+    fn default() -> Self {
+        Self::new(DEFAULT_SENSITIVITY, DEFAULT_MIN_INTERVAL)
+    }
+}
+
+impl Processor for BeatDetector {
+    fn process<S>(&mut self, data: &[S], config: &StreamConfig)
+    where
+        S: SizedSample + ToSample<f32>,
+    {
+        self.process_samples(data, config.channels as usize, config.sample_rate.0);
+    }
+
+    fn process_error(&mut self, _err: StreamError) {}
+
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    fn take_event(&mut self) -> Option<ThreadCommand> {
+        self.take_beat().map(ThreadCommand::Beat)
+    }
+}
@@ -0,0 +1,289 @@
+use std::{fs, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use log::warn;
+use serde::Deserialize;
+
+use crate::{
+    analyzer::AnalysisConfig,
+    audio_capture::{AudioConfig, AudioSource},
+    device::DeviceFilter,
+    generator::{GeneratorConfig, Signal},
+    keyboard::{ChannelMap, IdleConfig, OutputConfig, OutputMode, DEFAULT_CONTROL_TIMEOUT},
+    playback::PlaybackConfig,
+    scheduler::DEFAULT_SEND_RATE,
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to the config file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Keyboard vendor id, e.g. 0x19F5
+    #[arg(long, value_parser = parse_u16)]
+    pub vid: Option<u16>,
+    /// Keyboard product id, e.g. 0x3245
+    #[arg(long, value_parser = parse_u16)]
+    pub pid: Option<u16>,
+    /// Raw HID usage page
+    #[arg(long, value_parser = parse_u16)]
+    pub usage_page: Option<u16>,
+    /// Raw HID usage
+    #[arg(long, value_parser = parse_u16)]
+    pub usage: Option<u16>,
+    /// Keyboard serial number
+    #[arg(long)]
+    pub serial: Option<String>,
+    /// Platform specific HID device path
+    #[arg(long)]
+    pub path: Option<String>,
+    /// List matching keyboards and exit
+    #[arg(long)]
+    pub list_devices: bool,
+    /// Drive every matching keyboard instead of asking for one
+    #[arg(long)]
+    pub all_keyboards: bool,
+    /// HID reports per second sent to each keyboard, 0 sends every analysis
+    #[arg(long)]
+    pub rate: Option<u32>,
+    /// Milliseconds without reports before the firmware takes its lighting back
+    #[arg(long)]
+    pub control_timeout_ms: Option<u64>,
+    /// List audio hosts, devices and their configs and exit
+    #[arg(long)]
+    pub list_audio_devices: bool,
+    /// Audio host to capture from, e.g. ALSA, JACK or WASAPI
+    #[arg(long)]
+    pub audio_host: Option<String>,
+    /// Audio device name substring or index from --list-audio-devices
+    #[arg(long)]
+    pub audio_device: Option<String>,
+    /// Capture what is played on an output (loopback) or record an input
+    #[arg(long, value_enum)]
+    pub audio_source: Option<AudioSource>,
+    /// Play a WAV, FLAC or Ogg Vorbis file instead of capturing a device
+    #[arg(long)]
+    pub audio_file: Option<PathBuf>,
+    /// Playback speed of --audio-file, 0 plays as fast as the analysis keeps up
+    #[arg(long)]
+    pub speed: Option<f32>,
+    /// Start --audio-file over when it ends
+    #[arg(long)]
+    pub repeat: bool,
+    /// Analyze a built-in test signal instead of capturing a device
+    #[arg(long, value_enum)]
+    pub generator: Option<Signal>,
+    /// Tempo of the clicks generator
+    #[arg(long)]
+    pub bpm: Option<f32>,
+    /// Run without the terminal UI, e.g. as a background service.
+    /// Several matching keyboards need --all-keyboards or a narrower filter
+    #[arg(long)]
+    pub headless: bool,
+    /// Append log lines to this file instead of stderr
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+}
+
+impl Cli {
+    fn has_filter(&self) -> bool {
+        self.vid.is_some()
+            || self.pid.is_some()
+            || self.usage_page.is_some()
+            || self.usage.is_some()
+            || self.serial.is_some()
+            || self.path.is_some()
+    }
+
+    pub fn has_audio_choice(&self) -> bool {
+        self.audio_host.is_some() || self.audio_device.is_some() || self.audio_source.is_some()
+    }
+}
+
+#[derive(Clone, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct KeyboardConfig {
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub filter: DeviceFilter,
+    pub channels: ChannelMap,
+    pub mode: OutputMode,
+    pub rate: Option<u32>,
+    pub control_timeout_ms: Option<u64>,
+}
+
+impl KeyboardConfig {
+    pub fn output(&self) -> OutputConfig {
+        OutputConfig {
+            channels: self.channels,
+            mode: self.mode,
+            rate: self.rate.unwrap_or(DEFAULT_SEND_RATE),
+            control_timeout: self
+                .control_timeout_ms
+                .map_or(DEFAULT_CONTROL_TIMEOUT, Duration::from_millis),
+            ..OutputConfig::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub keyboard: DeviceFilter,
+    pub keyboards: Vec<KeyboardConfig>,
+    pub all_keyboards: bool,
+    pub rate: Option<u32>,
+    pub control_timeout_ms: Option<u64>,
+    pub idle: IdleConfig,
+    pub audio: AudioConfig,
+    pub playback: PlaybackConfig,
+    pub generator: GeneratorConfig,
+    pub analysis: AnalysisConfig,
+}
+
+impl Config {
+    pub fn load(cli: &Cli) -> Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => match Self::default_path() {
+                Some(path) if path.exists() => Self::from_file(&path)?,
+                _ => Self::default(),
+            },
+        };
+        config.apply_cli(cli);
+        // A choice on the command line is only remembered once it opened a device.
+        if !cli.has_audio_choice() && config.audio == AudioConfig::default() {
+            // The app writes this file itself, a broken copy is not worth failing for.
+            match Self::remembered_audio() {
+                Ok(Some(audio)) => config.audio = audio,
+                Ok(None) => (),
+                Err(err) => warn!("Ignoring the remembered audio device: {:#}", err),
+            }
+        }
+        for keyboard in &mut config.keyboards {
+            keyboard.rate = keyboard.rate.or(config.rate);
+            keyboard.control_timeout_ms = keyboard.control_timeout_ms.or(config.control_timeout_ms);
+        }
+        Ok(config)
+    }
+
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("qmk-colormusic").join("config.toml"))
+    }
+
+    fn remembered_audio_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("qmk-colormusic").join("audio.toml"))
+    }
+
+    fn remembered_audio() -> Result<Option<AudioConfig>> {
+        match Self::remembered_audio_path() {
+            Some(path) if path.exists() => {
+                let content = fs::read_to_string(&path)
+                    .with_context(|| format!("Cannot read {}", path.display()))?;
+                let audio = toml::from_str(&content)
+                    .with_context(|| format!("Cannot parse {}", path.display()))?;
+                Ok(Some(audio))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn remember_audio(audio: &AudioConfig) -> Result<()> {
+        let Some(path) = Self::remembered_audio_path() else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Cannot create {}", dir.display()))?;
+        }
+        fs::write(&path, toml::to_string(audio)?)
+            .with_context(|| format!("Cannot write {}", path.display()))
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read config file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("Cannot parse config file {}", path.display()))
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) {
+        self.all_keyboards |= cli.all_keyboards;
+        if cli.rate.is_some() {
+            self.rate = cli.rate;
+            for keyboard in &mut self.keyboards {
+                keyboard.rate = cli.rate;
+            }
+        }
+        if cli.control_timeout_ms.is_some() {
+            self.control_timeout_ms = cli.control_timeout_ms;
+            for keyboard in &mut self.keyboards {
+                keyboard.control_timeout_ms = cli.control_timeout_ms;
+            }
+        }
+        // Explicit filters on the command line take precedence over the configured list.
+        if cli.has_filter() {
+            self.keyboards.clear();
+        }
+        let keyboard = &mut self.keyboard;
+        if cli.vid.is_some() {
+            keyboard.vendor_id = cli.vid;
+        }
+        if cli.pid.is_some() {
+            keyboard.product_id = cli.pid;
+        }
+        if cli.usage_page.is_some() {
+            keyboard.usage_page = cli.usage_page;
+        }
+        if cli.usage.is_some() {
+            keyboard.usage = cli.usage;
+        }
+        if cli.serial.is_some() {
+            keyboard.serial.clone_from(&cli.serial);
+        }
+        if cli.path.is_some() {
+            keyboard.path.clone_from(&cli.path);
+        }
+        if cli.audio_host.is_some() {
+            self.audio.host.clone_from(&cli.audio_host);
+        }
+        if cli.audio_device.is_some() {
+            self.audio.device.clone_from(&cli.audio_device);
+        }
+        if let Some(source) = cli.audio_source {
+            self.audio.source = source;
+        }
+        // A source picked on the command line wins over configured ones.
+        if cli.has_audio_choice() || cli.generator.is_some() {
+            self.playback.file = None;
+        }
+        if cli.has_audio_choice() || cli.audio_file.is_some() {
+            self.generator.signal = None;
+        }
+        if cli.audio_file.is_some() {
+            self.playback.file.clone_from(&cli.audio_file);
+        }
+        if cli.generator.is_some() {
+            self.generator.signal = cli.generator;
+        }
+        if let Some(bpm) = cli.bpm {
+            self.generator.bpm = bpm;
+        }
+        if let Some(speed) = cli.speed {
+            self.playback.speed = speed;
+        }
+        self.playback.repeat |= cli.repeat;
+    }
+}
+
+fn parse_u16(value: &str) -> Result<u16, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse::<u16>(),
+    };
+    parsed.map_err(|err| format!("'{value}' is not a valid u16: {err}"))
+}
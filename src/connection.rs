@@ -0,0 +1,176 @@
+use std::{
+    fmt::Display,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use hidapi::{HidApi, HidDevice};
+
+use crate::{
+    analyzer::SharedSnapshot,
+    device::{self, DeviceFilter},
+    handshake::{process_handshake, FirmwareInfo, HandshakeConfig},
+    keyboard::{hid_thread, OutputConfig},
+    protocol::{Protocol, ThreadCommand},
+    scheduler::SharedSendStats,
+    transport::Transport,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Searching,
+    Handshaking(String),
+    Connected {
+        device: String,
+        firmware: FirmwareInfo,
+    },
+    Disconnected(String),
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Searching => write!(f, "Searching for keyboard..."),
+            ConnectionState::Handshaking(device) => write!(f, "Handshaking with {}", device),
+            ConnectionState::Connected { device, firmware } => write!(
+                f,
+                "Connected to {} (protocol v{})",
+                device, firmware.version
+            ),
+            ConnectionState::Disconnected(reason) => write!(f, "Disconnected: {}", reason),
+        }
+    }
+}
+
+pub type SharedConnectionState = Arc<Mutex<ConnectionState>>;
+
+pub struct Supervisor {
+    pub protocol: Protocol,
+    pub handshake: HandshakeConfig,
+    pub rescan_interval: Duration,
+    pub output: OutputConfig,
+    state: SharedConnectionState,
+    stats: SharedSendStats,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self {
+            protocol: Protocol::default(),
+            handshake: HandshakeConfig::default(),
+            rescan_interval: Duration::from_secs(1),
+            output: OutputConfig::default(),
+            state: Arc::new(Mutex::new(ConnectionState::Searching)),
+            stats: SharedSendStats::default(),
+        }
+    }
+
+    pub fn state(&self) -> SharedConnectionState {
+        self.state.clone()
+    }
+
+    pub fn stats(&self) -> SharedSendStats {
+        self.stats.clone()
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    // Returns false once asked to stop or the sender side of the channel is gone.
+    fn wait(&self, rx: &Receiver<ThreadCommand>) -> bool {
+        match rx.recv_timeout(self.rescan_interval) {
+            Ok(ThreadCommand::Stop) | Err(RecvTimeoutError::Disconnected) => false,
+            Ok(_) | Err(RecvTimeoutError::Timeout) => rx
+                .try_iter()
+                .all(|command| !matches!(command, ThreadCommand::Stop)),
+        }
+    }
+
+    pub fn run<T, F>(
+        &self,
+        mut connect: F,
+        snapshot: SharedSnapshot,
+        rx: Receiver<ThreadCommand>,
+    ) -> Result<()>
+    where
+        T: Transport,
+        F: FnMut() -> Result<Option<(String, T)>>,
+    {
+        loop {
+            self.set_state(ConnectionState::Searching);
+            let session = match connect() {
+                Ok(Some((name, transport))) => self
+                    .session(name, &transport, snapshot.clone(), &rx)
+                    .map(Some),
+                Ok(None) => Ok(None),
+                Err(err) => Err(err),
+            };
+            match session {
+                Ok(Some(())) => return Ok(()),
+                Ok(None) => (),
+                Err(err) => self.set_state(ConnectionState::Disconnected(err.to_string())),
+            }
+            if !self.wait(&rx) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn session<T>(
+        &self,
+        name: String,
+        transport: &T,
+        snapshot: SharedSnapshot,
+        rx: &Receiver<ThreadCommand>,
+    ) -> Result<()>
+    where
+        T: Transport,
+    {
+        self.set_state(ConnectionState::Handshaking(name.clone()));
+        let firmware = process_handshake(transport, &self.protocol, &self.handshake)?;
+        self.set_state(ConnectionState::Connected {
+            device: name,
+            firmware,
+        });
+        hid_thread(
+            transport,
+            &self.protocol,
+            snapshot,
+            rx,
+            &firmware,
+            &self.output,
+            &self.stats,
+        )
+    }
+
+    pub fn run_hid(
+        &self,
+        filter: DeviceFilter,
+        snapshot: SharedSnapshot,
+        rx: Receiver<ThreadCommand>,
+    ) -> Result<()> {
+        let mut hidapi = HidApi::new()?;
+        let connect = move || -> Result<Option<(String, HidDevice)>> {
+            hidapi.refresh_devices()?;
+            match device::find_devices(&hidapi, &filter).first() {
+                Some(info) => Ok(Some((
+                    device::short_name(info),
+                    device::open_device(&hidapi, info)?,
+                ))),
+                None => Ok(None),
+            }
+        };
+        self.run(connect, snapshot, rx)
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
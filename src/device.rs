@@ -0,0 +1,123 @@
+use std::io::{self, BufRead, Write};
+
+use anyhow::{bail, Context, Result};
+use hidapi::{DeviceInfo, HidApi};
+use serde::Deserialize;
+
+pub const RAW_HID_USAGE_PAGE: u16 = 0xFF60;
+pub const RAW_HID_USAGE: u16 = 0x61;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct DeviceFilter {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub usage_page: Option<u16>,
+    pub usage: Option<u16>,
+    pub serial: Option<String>,
+    pub path: Option<String>,
+}
+
+impl DeviceFilter {
+    pub fn new() -> Self {
+        Self {
+            vendor_id: None,
+            product_id: None,
+            usage_page: Some(RAW_HID_USAGE_PAGE),
+            usage: Some(RAW_HID_USAGE),
+            serial: None,
+            path: None,
+        }
+    }
+
+    pub fn pinned_to(&self, info: &DeviceInfo) -> Self {
+        let mut filter = self.clone();
+        filter.vendor_id = Some(info.vendor_id());
+        filter.product_id = Some(info.product_id());
+        match info.serial_number() {
+            Some(serial) if !serial.is_empty() => filter.serial = Some(serial.to_owned()),
+            _ => filter.path = Some(info.path().to_string_lossy().into_owned()),
+        }
+        filter
+    }
+
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        self.vendor_id.is_none_or(|vid| info.vendor_id() == vid)
+            && self.product_id.is_none_or(|pid| info.product_id() == pid)
+            && self
+                .usage_page
+                .is_none_or(|usage_page| info.usage_page() == usage_page)
+            && self.usage.is_none_or(|usage| info.usage() == usage)
+            && self
+                .serial
+                .as_deref()
+                .is_none_or(|serial| info.serial_number() == Some(serial))
+            && self
+                .path
+                .as_deref()
+                .is_none_or(|path| info.path().to_string_lossy() == path)
+    }
+}
+
+impl Default for DeviceFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn find_devices<'a>(hidapi: &'a HidApi, filter: &DeviceFilter) -> Vec<&'a DeviceInfo> {
+    hidapi
+        .device_list()
+        .filter(|info| filter.matches(info))
+        .collect()
+}
+
+pub fn describe(info: &DeviceInfo) -> String {
+    format!(
+        "VID: {:04x}, PID: {:04x}, {} {}, serial: {}, path: {}",
+        info.vendor_id(),
+        info.product_id(),
+        info.manufacturer_string().unwrap_or("Unknown"),
+        info.product_string().unwrap_or("device"),
+        info.serial_number().unwrap_or("-"),
+        info.path().to_string_lossy()
+    )
+}
+
+pub fn short_name(info: &DeviceInfo) -> String {
+    match info.product_string() {
+        Some(product) if !product.is_empty() => product.to_owned(),
+        _ => format!("{:04x}:{:04x}", info.vendor_id(), info.product_id()),
+    }
+}
+
+pub fn select_device<'a>(devices: &[&'a DeviceInfo]) -> Result<&'a DeviceInfo> {
+    match devices {
+        [] => bail!("Cannot find keyboard device"),
+        [device] => Ok(device),
+        _ => {
+            println!("Several keyboards match:");
+            for (index, device) in devices.iter().enumerate() {
+                println!(" [{}] {}", index, describe(device));
+            }
+            let stdin = io::stdin();
+            loop {
+                print!("Select device [0-{}]: ", devices.len() - 1);
+                io::stdout().flush()?;
+                let mut line = String::new();
+                if stdin.lock().read_line(&mut line)? == 0 {
+                    bail!("No device selected");
+                }
+                match line.trim().parse::<usize>() {
+                    Ok(index) if index < devices.len() => return Ok(devices[index]),
+                    _ => println!("Invalid selection '{}'", line.trim()),
+                }
+            }
+        }
+    }
+}
+
+pub fn open_device(hidapi: &HidApi, info: &DeviceInfo) -> Result<hidapi::HidDevice> {
+    info.open_device(hidapi)
+        .with_context(|| format!("Cannot open device {}", describe(info)))
+}
@@ -0,0 +1,142 @@
+use std::{
+    fs::File,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error,
+    formats::{FormatOptions, FormatReader},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use crate::playback::SampleSource;
+
+// Decodes WAV, FLAC and Ogg Vorbis files into interleaved f32 samples.
+pub struct FileSource {
+    path: PathBuf,
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    config: StreamConfig,
+    pending: Vec<f32>,
+}
+
+impl FileSource {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Cannot open {}", path.display()))?;
+        let stream = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(extension);
+        }
+        let probed = symphonia::default::get_probe()
+            .format(
+                &hint,
+                stream,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .with_context(|| format!("Unsupported audio file {}", path.display()))?;
+
+        let format = probed.format;
+        let track = format
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .with_context(|| format!("No audio track in {}", path.display()))?;
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .with_context(|| format!("Unsupported codec in {}", path.display()))?;
+        let track_id = track.id;
+        let sample_rate = track.codec_params.sample_rate.unwrap_or_default();
+        let channels = track
+            .codec_params
+            .channels
+            .map_or(0, |channels| channels.count());
+
+        let mut source = Self {
+            path: path.to_owned(),
+            format,
+            decoder,
+            track_id,
+            config: StreamConfig {
+                channels: channels as u16,
+                sample_rate: SampleRate(sample_rate),
+                buffer_size: BufferSize::Default,
+            },
+            pending: Vec::new(),
+        };
+        // Some containers only know the signal layout after the first packet.
+        if sample_rate == 0 || channels == 0 {
+            let mut pending = Vec::new();
+            source.decode(&mut pending)?;
+            source.pending = pending;
+        }
+        if source.config.sample_rate.0 == 0 || source.config.channels == 0 {
+            bail!("Cannot tell the sample rate of {}", path.display());
+        }
+        Ok(source)
+    }
+
+    pub fn name(&self) -> String {
+        self.path.file_name().map_or_else(
+            || self.path.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        )
+    }
+
+    fn decode(&mut self, buffer: &mut Vec<f32>) -> Result<bool> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                    return Ok(false)
+                }
+                Err(err) => return Err(err.into()),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let spec = *decoded.spec();
+                    self.config.channels = spec.channels.count() as u16;
+                    self.config.sample_rate = SampleRate(spec.rate);
+                    let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+                    samples.copy_interleaved_ref(decoded);
+                    buffer.extend_from_slice(samples.samples());
+                    return Ok(true);
+                }
+                // A corrupt packet is skipped, like a glitch in a live stream.
+                Err(Error::DecodeError(_)) => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+impl SampleSource for FileSource {
+    fn config(&self) -> &StreamConfig {
+        &self.config
+    }
+
+    fn read(&mut self, buffer: &mut Vec<f32>) -> Result<bool> {
+        if !self.pending.is_empty() {
+            buffer.append(&mut self.pending);
+            return Ok(true);
+        }
+        self.decode(buffer)
+    }
+
+    fn rewind(&mut self) -> Result<()> {
+        *self = Self::open(&self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use std::{f32::consts::TAU, fmt::Display};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use serde::Deserialize;
+
+use crate::playback::SampleSource;
+
+const BLOCK_DURATION: f32 = 0.01;
+const CLICK_DURATION: f32 = 0.005;
+const STEP_FREQUENCY: f32 = 1000.0;
+const STEP_LEVELS_DB: [f32; 7] = [-60.0, -48.0, -36.0, -24.0, -12.0, -6.0, 0.0];
+const NOISE_SEED: u32 = 0x2545_F491;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Signal {
+    #[default]
+    Sweep,
+    White,
+    Pink,
+    Clicks,
+    Steps,
+}
+
+impl Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Signal::Sweep => write!(f, "sine sweep"),
+            Signal::White => write!(f, "white noise"),
+            Signal::Pink => write!(f, "pink noise"),
+            Signal::Clicks => write!(f, "clicks"),
+            Signal::Steps => write!(f, "stepped levels"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct GeneratorConfig {
+    pub signal: Option<Signal>,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub amplitude: f32,
+    pub min_frequency: f32,
+    pub max_frequency: f32,
+    pub bpm: f32,
+    // Length of one sweep or one pass over all steps.
+    pub period_secs: f32,
+    // Zero generates forever.
+    pub duration_secs: f32,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            signal: None,
+            sample_rate: 48000,
+            channels: 2,
+            amplitude: 0.5,
+            min_frequency: 20.0,
+            max_frequency: 20000.0,
+            bpm: 120.0,
+            period_secs: 10.0,
+            duration_secs: 0.0,
+        }
+    }
+}
+
+pub struct Generator {
+    signal: Signal,
+    settings: GeneratorConfig,
+    config: StreamConfig,
+    frame: u64,
+    phase: f32,
+    noise: u32,
+    pink: [f32; 7],
+}
+
+impl Generator {
+    pub fn new(signal: Signal, settings: &GeneratorConfig) -> Self {
+        Self {
+            signal,
+            settings: settings.clone(),
+            config: StreamConfig {
+                channels: settings.channels.max(1),
+                sample_rate: SampleRate(settings.sample_rate.max(1)),
+                buffer_size: BufferSize::Default,
+            },
+            frame: 0,
+            phase: 0.0,
+            noise: NOISE_SEED,
+            pink: [0.0; 7],
+        }
+    }
+
+    pub fn name(&self) -> String {
+        format!("{} generator", self.signal)
+    }
+
+    // Kept in f64 so long runs do not lose timing precision.
+    fn time(&self) -> f64 {
+        self.frame as f64 / self.config.sample_rate.0 as f64
+    }
+
+    // Xorshift keeps the noise identical between runs.
+    fn white(&mut self) -> f32 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+
+    // Paul Kellet's refined filter, roughly -3 dB per octave.
+    fn pink(&mut self) -> f32 {
+        let white = self.white();
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        (pink * 0.11).clamp(-1.0, 1.0)
+    }
+
+    fn sine(&mut self, frequency: f32) -> f32 {
+        let sample = self.phase.sin();
+        self.phase = (self.phase + TAU * frequency / self.config.sample_rate.0 as f32) % TAU;
+        sample
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        let settings = &self.settings;
+        let period = settings.period_secs.max(BLOCK_DURATION) as f64;
+        let position = (self.time() % period / period) as f32;
+        let amplitude = settings.amplitude;
+        match self.signal {
+            Signal::Sweep => {
+                let (min, max) = (settings.min_frequency.max(1.0), settings.max_frequency);
+                let frequency = min * (max / min).powf(position);
+                amplitude * self.sine(frequency)
+            }
+            Signal::White => amplitude * self.white(),
+            Signal::Pink => amplitude * self.pink(),
+            Signal::Clicks => {
+                let beat = 60.0 / settings.bpm.max(1.0) as f64;
+                if self.time() % beat < CLICK_DURATION as f64 {
+                    amplitude
+                } else {
+                    0.0
+                }
+            }
+            Signal::Steps => {
+                let step = (position * STEP_LEVELS_DB.len() as f32) as usize;
+                let level = 10f32.powf(STEP_LEVELS_DB[step.min(STEP_LEVELS_DB.len() - 1)] / 20.0);
+                amplitude * level * self.sine(STEP_FREQUENCY)
+            }
+        }
+    }
+}
+
+impl SampleSource for Generator {
+    fn config(&self) -> &StreamConfig {
+        &self.config
+    }
+
+    fn read(&mut self, buffer: &mut Vec<f32>) -> Result<bool> {
+        let rate = self.config.sample_rate.0 as f32;
+        let mut frames = (rate * BLOCK_DURATION).max(1.0) as u64;
+        if self.settings.duration_secs > 0.0 {
+            let total = (self.settings.duration_secs * rate) as u64;
+            frames = frames.min(total.saturating_sub(self.frame));
+            if frames == 0 {
+                return Ok(false);
+            }
+        }
+        for _ in 0..frames {
+            let sample = self.next_sample();
+            buffer.extend(std::iter::repeat_n(sample, self.config.channels as usize));
+            self.frame += 1;
+        }
+        Ok(true)
+    }
+
+    fn rewind(&mut self) -> Result<()> {
+        *self = Self::new(self.signal, &self.settings);
+        Ok(())
+    }
+}
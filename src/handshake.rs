@@ -0,0 +1,210 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    protocol::{
+        Capabilities, Command, Protocol, LEGACY_PROTOCOL_VERSION, PAGE_SIZE, PROTOCOL_VERSION,
+    },
+    transport::Transport,
+};
+
+const REQUEST_STATUS: u8 = 0x7F;
+const RESPONSE_STATUS: u8 = 0x80;
+const CONFIRM_STATUS: u8 = 0x81;
+
+#[derive(Clone, Copy, Debug)]
+pub struct HandshakeConfig {
+    pub deadline: Duration,
+    pub response_timeout: Duration,
+    pub retries: u32,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self {
+            deadline: Duration::from_secs(5),
+            response_timeout: Duration::from_millis(500),
+            retries: 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    pub version: u8,
+    pub capabilities: Capabilities,
+}
+
+impl FirmwareInfo {
+    pub fn legacy() -> Self {
+        Self {
+            version: LEGACY_PROTOCOL_VERSION,
+            capabilities: Capabilities::legacy(),
+        }
+    }
+
+    pub fn supports(&self, command: &Command) -> bool {
+        self.capabilities.supports(command)
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Transport(anyhow::Error),
+    Timeout,
+    RetriesExhausted(u32),
+    NoAnswer(u32),
+    UnsupportedVersion(u8),
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::Transport(err) => write!(f, "Transport error: {}", err),
+            HandshakeError::Timeout => write!(f, "Keyboard did not respond in time"),
+            HandshakeError::RetriesExhausted(attempts) => {
+                write!(f, "Keyboard rejected handshake after {} attempts", attempts)
+            }
+            HandshakeError::NoAnswer(attempts) => {
+                write!(f, "Keyboard did not answer after {} attempts", attempts)
+            }
+            HandshakeError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported firmware protocol version {}", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl From<anyhow::Error> for HandshakeError {
+    fn from(err: anyhow::Error) -> Self {
+        HandshakeError::Transport(err)
+    }
+}
+
+enum State {
+    // Rejected records whether the keyboard ever answered with a wrong status.
+    Request {
+        attempt: u32,
+        rejected: bool,
+    },
+    AwaitResponse {
+        attempt: u32,
+        until: Instant,
+        rejected: bool,
+    },
+    Negotiate {
+        until: Instant,
+    },
+    Done(FirmwareInfo),
+}
+
+pub fn process_handshake<T>(
+    transport: &T,
+    protocol: &Protocol,
+    config: &HandshakeConfig,
+) -> Result<FirmwareInfo, HandshakeError>
+where
+    T: Transport + ?Sized,
+{
+    let deadline = Instant::now() + config.deadline;
+    let send = |command: Command| -> Result<(), HandshakeError> {
+        let report = protocol
+            .prepare_command(&command)
+            .map_err(anyhow::Error::from)?;
+        transport.write_report(&report)?;
+        Ok(())
+    };
+    let receive = |until: Instant| -> Result<Option<Command>, HandshakeError> {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(HandshakeError::Timeout);
+        }
+        let mut hid_buffer = [0; PAGE_SIZE];
+        let timeout = until.min(deadline).saturating_duration_since(now);
+        let bytes = transport.read_report(&mut hid_buffer, Some(timeout))?;
+        if bytes == 0 {
+            return Ok(None);
+        }
+        // Malformed reports are skipped, the attempt timeout still applies.
+        Ok(protocol.to_command(&hid_buffer[0..bytes]).ok())
+    };
+
+    let mut state = State::Request {
+        attempt: 0,
+        rejected: false,
+    };
+    loop {
+        state = match state {
+            State::Request { attempt, rejected } if attempt >= config.retries => {
+                return Err(if rejected {
+                    HandshakeError::RetriesExhausted(attempt)
+                } else {
+                    HandshakeError::NoAnswer(attempt)
+                });
+            }
+            State::Request { attempt, rejected } => {
+                send(Command::Handshake {
+                    status: REQUEST_STATUS,
+                })?;
+                State::AwaitResponse {
+                    attempt: attempt + 1,
+                    until: Instant::now() + config.response_timeout,
+                    rejected,
+                }
+            }
+            State::AwaitResponse {
+                attempt,
+                until,
+                rejected,
+            } => match receive(until)? {
+                Some(Command::Handshake {
+                    status: RESPONSE_STATUS,
+                }) => {
+                    send(Command::Handshake {
+                        status: CONFIRM_STATUS,
+                    })?;
+                    send(Command::Version {
+                        version: PROTOCOL_VERSION,
+                        capabilities: Capabilities::host().0,
+                    })?;
+                    State::Negotiate {
+                        until: Instant::now() + config.response_timeout,
+                    }
+                }
+                Some(Command::Handshake { .. }) => State::Request {
+                    attempt,
+                    rejected: true,
+                },
+                None if Instant::now() >= until => State::Request { attempt, rejected },
+                _ => State::AwaitResponse {
+                    attempt,
+                    until,
+                    rejected,
+                },
+            },
+            State::Negotiate { until } => match receive(until) {
+                Ok(Some(Command::Version {
+                    version,
+                    capabilities,
+                })) => {
+                    if version < LEGACY_PROTOCOL_VERSION {
+                        return Err(HandshakeError::UnsupportedVersion(version));
+                    }
+                    State::Done(FirmwareInfo {
+                        version,
+                        capabilities: Capabilities(capabilities),
+                    })
+                }
+                Ok(None) if Instant::now() >= until => State::Done(FirmwareInfo::legacy()),
+                Ok(_) => State::Negotiate { until },
+                Err(HandshakeError::Timeout) => State::Done(FirmwareInfo::legacy()),
+                Err(err) => return Err(err),
+            },
+            State::Done(firmware) => return Ok(firmware),
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::{
+    analyzer::SharedSnapshot,
+    handshake::FirmwareInfo,
+    protocol::{
+        bands_command, beat_command, frame_commands, Capabilities, Command, Protocol,
+        ThreadCommand, PAGE_SIZE,
+    },
+    scheduler::{Scheduler, SharedSendStats, DEFAULT_SEND_RATE},
+    transport::Transport,
+    visualizer::{to_rgb, Layout, VUMeterEmulator},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelMap {
+    #[default]
+    Stereo,
+    Swapped,
+    Left,
+    Right,
+    Mono,
+}
+
+impl ChannelMap {
+    pub fn apply(&self, rms: (f32, f32)) -> (f32, f32) {
+        match self {
+            ChannelMap::Stereo => rms,
+            ChannelMap::Swapped => (rms.1, rms.0),
+            ChannelMap::Left => (rms.0, rms.0),
+            ChannelMap::Right => (rms.1, rms.1),
+            ChannelMap::Mono => {
+                let mono = (rms.0 + rms.1) / 2.0f32;
+                (mono, mono)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    #[default]
+    Rms,
+    Frame,
+    Bands,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputConfig {
+    pub channels: ChannelMap,
+    pub mode: OutputMode,
+    pub rate: u32,
+    pub idle: IdleConfig,
+    pub control_timeout: Duration,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            channels: ChannelMap::default(),
+            mode: OutputMode::default(),
+            rate: DEFAULT_SEND_RATE,
+            idle: IdleConfig::default(),
+            control_timeout: DEFAULT_CONTROL_TIMEOUT,
+        }
+    }
+}
+
+pub const DEFAULT_CONTROL_TIMEOUT: Duration = Duration::from_secs(2);
+const HEARTBEATS_PER_TIMEOUT: u32 = 4;
+const EVENT_POLL: Duration = Duration::from_millis(20);
+const SENSITIVITY_GAINS: [f32; 3] = [1.0, 2.0, 4.0];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleAction {
+    None,
+    #[default]
+    Animation,
+    Release,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct IdleConfig {
+    pub action: IdleAction,
+    pub delay_secs: u64,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self {
+            action: IdleAction::default(),
+            delay_secs: DEFAULT_IDLE_DELAY_SECS,
+        }
+    }
+}
+
+const DEFAULT_IDLE_DELAY_SECS: u64 = 30;
+const IDLE_BRIGHTNESS: f32 = 0.25;
+const IDLE_PERIOD: f32 = 4.0;
+
+fn idle_commands(mode: OutputMode, time: f32, bands: usize, layout: &mut Layout) -> Vec<Command> {
+    let phase = time * std::f32::consts::TAU / IDLE_PERIOD;
+    match mode {
+        OutputMode::Rms => {
+            let level = ((phase.sin() + 1.0) / 2.0 * IDLE_BRIGHTNESS * 255.0) as u8;
+            vec![Command::RMS {
+                left: level,
+                right: level,
+            }]
+        }
+        OutputMode::Frame => {
+            let step = 360.0 / layout.colors.len() as f32;
+            for (index, color) in layout.colors.iter_mut().enumerate() {
+                let hue = (time * 360.0 / IDLE_PERIOD + index as f32 * step) % 360.0;
+                *color = to_rgb(hue, 1.0, IDLE_BRIGHTNESS);
+            }
+            frame_commands(&layout.rgb_frame())
+        }
+        OutputMode::Bands => {
+            let values: Vec<u8> = (0..bands.max(1))
+                .map(|band| {
+                    let wave = (phase - band as f32 * 0.5).sin();
+                    ((wave + 1.0) / 2.0 * IDLE_BRIGHTNESS * 255.0) as u8
+                })
+                .collect();
+            vec![bands_command(&values)]
+        }
+    }
+}
+
+// Without a release command, levels at rest are the closest to the firmware's own lighting.
+fn rest_commands(mode: OutputMode, bands: usize) -> Vec<Command> {
+    match mode {
+        OutputMode::Rms => vec![Command::RMS { left: 0, right: 0 }],
+        OutputMode::Frame => frame_commands(&Layout::default().rgb_frame()),
+        OutputMode::Bands => vec![bands_command(&vec![0; bands.max(1)])],
+    }
+}
+
+fn send<T>(transport: &T, protocol: &Protocol, commands: &[Command]) -> Result<()>
+where
+    T: Transport + ?Sized,
+{
+    for command in commands {
+        transport.write_report(&protocol.prepare_command(command)?)?;
+    }
+    Ok(())
+}
+
+fn read_events<T>(transport: &T, protocol: &Protocol) -> Result<Vec<Command>>
+where
+    T: Transport + ?Sized,
+{
+    let mut events = Vec::new();
+    let mut report = [0u8; PAGE_SIZE];
+    loop {
+        let length = transport.read_report(&mut report, Some(Duration::ZERO))?;
+        if length == 0 {
+            return Ok(events);
+        }
+        // Late replies and garbled reports are no events, the rest still is.
+        if let Ok(command) = protocol.to_command(&report[..length]) {
+            if command.is_event() {
+                events.push(command);
+            }
+        }
+    }
+}
+
+// The part of the output the keyboard itself can change through events.
+struct Effects {
+    modes: Vec<OutputMode>,
+    mode: OutputMode,
+    gain: usize,
+    paused: bool,
+}
+
+impl Effects {
+    fn new(mode: OutputMode, firmware: &FirmwareInfo) -> Self {
+        let mut modes = vec![OutputMode::Rms];
+        if firmware.capabilities.has(Capabilities::CUSTOM_DATA) {
+            modes.push(OutputMode::Frame);
+        }
+        if firmware.capabilities.has(Capabilities::BANDS) {
+            modes.push(OutputMode::Bands);
+        }
+        let mode = if modes.contains(&mode) {
+            mode
+        } else {
+            OutputMode::Rms
+        };
+        Self {
+            modes,
+            mode,
+            gain: 0,
+            paused: false,
+        }
+    }
+
+    fn apply(&mut self, event: &Command) {
+        match event {
+            Command::CycleMode => {
+                let index = self.modes.iter().position(|m| *m == self.mode).unwrap_or(0);
+                self.mode = self.modes[(index + 1) % self.modes.len()];
+            }
+            Command::Sensitivity => self.gain = (self.gain + 1) % SENSITIVITY_GAINS.len(),
+            Command::Pause => self.paused = !self.paused,
+            _ => (),
+        }
+    }
+
+    fn gain(&self) -> f32 {
+        SENSITIVITY_GAINS[self.gain]
+    }
+
+    fn level(&self, level: (f32, f32)) -> (f32, f32) {
+        let gain = self.gain();
+        ((level.0 * gain).min(1.0), (level.1 * gain).min(1.0))
+    }
+
+    fn bands(&self, bands: &[u8]) -> Vec<u8> {
+        let gain = self.gain();
+        bands
+            .iter()
+            .map(|band| (*band as f32 * gain).min(255.0) as u8)
+            .collect()
+    }
+}
+
+pub fn hid_thread<T>(
+    transport: &T,
+    protocol: &Protocol,
+    snapshot: SharedSnapshot,
+    rx: &Receiver<ThreadCommand>,
+    firmware: &FirmwareInfo,
+    output: &OutputConfig,
+    stats: &SharedSendStats,
+) -> Result<()>
+where
+    T: Transport + ?Sized,
+{
+    let mut effects = Effects::new(output.mode, firmware);
+    let mut layout = Layout::default();
+    let mut vu_emulator = VUMeterEmulator::default();
+    let meter_gain = vu_emulator.average_gain;
+    let mut scheduler = Scheduler::new(output.rate, stats.clone());
+    let started = Instant::now();
+    let events = firmware.capabilities.has(Capabilities::EVENTS);
+
+    // Firmware with control support falls back to its own lighting if the host goes silent.
+    let control = firmware.capabilities.has(Capabilities::CONTROL);
+    let timeout = (output.control_timeout.as_millis() / 100).clamp(1, u8::MAX as u128) as u8;
+    let take = Command::Take { timeout };
+    let heartbeat = Duration::from_millis(timeout as u64 * 100) / HEARTBEATS_PER_TIMEOUT;
+    let mut released = false;
+    if control {
+        send(transport, protocol, &[take])?;
+    }
+    let mut last_report = Instant::now();
+    loop {
+        let heartbeat_timeout =
+            (control && !released).then(|| heartbeat.saturating_sub(last_report.elapsed()));
+        let mut timeout = match (scheduler.timeout(), heartbeat_timeout) {
+            (Some(scheduled), Some(heartbeat)) => Some(scheduled.min(heartbeat)),
+            (scheduled, heartbeat) => scheduled.or(heartbeat),
+        };
+        if events {
+            timeout = Some(timeout.map_or(EVENT_POLL, |timeout| timeout.min(EVENT_POLL)));
+        }
+        let received = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let disconnected = match received {
+            Ok(ThreadCommand::ProcessorComplete) => {
+                scheduler.mark();
+                false
+            }
+            Ok(ThreadCommand::Beat(beat)) => {
+                let command = beat_command(&beat);
+                if firmware.supports(&command) && !released && !effects.paused {
+                    send(transport, protocol, &[command])?;
+                    last_report = Instant::now();
+                }
+                false
+            }
+            Ok(ThreadCommand::Stop) => {
+                let commands = match (control, released) {
+                    (true, true) => Vec::new(),
+                    (true, false) => vec![Command::Release],
+                    (false, _) => rest_commands(effects.mode, snapshot.load().bands.len()),
+                };
+                return send(transport, protocol, &commands);
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => {
+                scheduler.flush();
+                true
+            }
+        };
+        if events {
+            for event in read_events(transport, protocol)? {
+                effects.apply(&event);
+            }
+        }
+        if scheduler.is_due() {
+            let analysis = snapshot.load();
+            let mode = effects.mode;
+            let idle = output.idle.action != IdleAction::None
+                && analysis.silence >= Duration::from_secs(output.idle.delay_secs);
+            let release =
+                control && (effects.paused || idle && output.idle.action == IdleAction::Release);
+            if release != released {
+                send(
+                    transport,
+                    protocol,
+                    &[if release { Command::Release } else { take }],
+                )?;
+                last_report = Instant::now();
+                released = release;
+            }
+            let commands = match (effects.paused, idle, output.idle.action, mode) {
+                (true, _, _, _) if control => Vec::new(),
+                (true, _, _, mode) => rest_commands(mode, analysis.bands.len()),
+                (false, true, IdleAction::Animation, mode) => idle_commands(
+                    mode,
+                    started.elapsed().as_secs_f32(),
+                    analysis.bands.len(),
+                    &mut layout,
+                ),
+                (false, true, _, _) if control => Vec::new(),
+                (false, true, _, mode) => rest_commands(mode, analysis.bands.len()),
+                (false, false, _, OutputMode::Rms) => {
+                    let rms = output.channels.apply(effects.level(analysis.level));
+                    vec![Command::RMS {
+                        left: (rms.0 * 255f32) as u8,
+                        right: (rms.1 * 255f32) as u8,
+                    }]
+                }
+                (false, false, _, OutputMode::Frame) => {
+                    // The meter scales to its own running average, so the gain narrows its range.
+                    vu_emulator.average_gain = meter_gain / effects.gain();
+                    let level = output.channels.apply(analysis.level);
+                    vu_emulator.process(level, &mut layout.colors);
+                    frame_commands(&layout.rgb_frame())
+                }
+                (false, false, _, OutputMode::Bands) => {
+                    vec![bands_command(&effects.bands(&analysis.bands))]
+                }
+            };
+            if scheduler.take(&commands) {
+                send(transport, protocol, &commands)?;
+                last_report = Instant::now();
+            }
+        }
+        if disconnected {
+            // The host is gone for good, so the firmware need not wait for the timeout.
+            if control && !released {
+                send(transport, protocol, &[Command::Release])?;
+            }
+            return Ok(());
+        }
+        // Unchanged levels are not resent, so the firmware needs a sign of life.
+        if control && !released && last_report.elapsed() >= heartbeat {
+            send(transport, protocol, &[Command::Heartbeat])?;
+            last_report = Instant::now();
+        }
+    }
+}
+
+pub fn fan_out<T>(rx: Receiver<T>, mut senders: Vec<Sender<T>>)
+where
+    T: Clone,
+{
+    while let Ok(command) = rx.recv() {
+        senders.retain(|tx| tx.send(command.clone()).is_ok());
+        if senders.is_empty() {
+            return;
+        }
+    }
+}
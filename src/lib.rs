@@ -0,0 +1,18 @@
+pub mod analyzer;
+pub mod audio_capture;
+pub mod bands;
+pub mod beat;
+pub mod config;
+pub mod connection;
+pub mod device;
+pub mod file_source;
+pub mod generator;
+pub mod handshake;
+pub mod keyboard;
+pub mod pipeline;
+pub mod playback;
+pub mod protocol;
+pub mod scheduler;
+pub mod spectrum;
+pub mod transport;
+pub mod visualizer;
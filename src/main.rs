@@ -1,124 +1,315 @@
-pub mod audio_capture;
-pub mod protocol;
-pub mod visualizer;
-
 use std::{
+    fs::OpenOptions,
     io::{self, Stdout},
+    path::Path,
     sync::{
+        atomic::{AtomicBool, Ordering},
         mpsc::{self, Receiver, Sender},
-        Arc, Mutex,
+        Arc,
     },
-    time::Duration,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use clap::Parser;
 use crossterm::{
-    event::{self, Event, KeyCode},
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use hidapi::HidApi;
+use log::{info, warn, LevelFilter};
 use ratatui::prelude::*;
 
-use crate::protocol::{Command, Protocol, ThreadCommand, PAGE_SIZE};
-
-use self::{
-    audio_capture::{capture_device_ouput, get_default_audio_output_device, RmsProcessor},
-    visualizer::{LayoutWidget, VUMeterEmulator},
+use qmk_colormusic::{
+    analyzer::{Analyzer, Snapshot},
+    audio_capture::{
+        describe_audio_devices, find_host, get_audio_device_names, AudioCapture, AudioConfig,
+        AudioSource, CaptureStatus,
+    },
+    config::{Cli, Config, KeyboardConfig},
+    connection::{ConnectionState, SharedConnectionState, Supervisor},
+    device,
+    file_source::FileSource,
+    generator::Generator,
+    keyboard::fan_out,
+    pipeline::Pipeline,
+    protocol::ThreadCommand,
+    scheduler::SharedSendStats,
+    visualizer::{
+        self, ChannelMetersWidget, DevicePicker, DevicePickerWidget, LayoutWidget, VUMeterEmulator,
+    },
 };
 
+const DEFAULT_DEVICE_CHECK: Duration = Duration::from_secs(1);
+const HEADLESS_POLL: Duration = Duration::from_millis(50);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+const SHUTDOWN_POLL: Duration = Duration::from_millis(10);
+
 fn main() -> Result<()> {
-    let processor = Arc::new(Mutex::new(RmsProcessor::new()));
-    const VENDOR_ID: u16 = 0x19F5;
-    const PRODUCT_ID: u16 = 0x3245;
-    const USAGE_PAGE: u16 = 0xFF60;
-    const USAGE: u16 = 0x61;
+    let cli = Cli::parse();
+    setup_logging(cli.log_file.as_deref())?;
+    let config = Config::load(&cli)?;
+
+    if cli.list_audio_devices {
+        for line in describe_audio_devices() {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
 
     let hidapi = HidApi::new()?;
-    let device_info = hidapi
-        .device_list()
-        .find(|info| {
-            info.product_id() == PRODUCT_ID
-                && info.vendor_id() == VENDOR_ID
-                && info.usage() == USAGE
-                && info.usage_page() == USAGE_PAGE
-        })
-        .context("Cannot find keyboard device")?;
-
-    println!(
-        "Opening device:\n VID: {:04x}, PID: {:04x}\n",
-        device_info.vendor_id(),
-        device_info.product_id()
-    );
-
-    let hid_device = device_info.open_device(&hidapi)?;
+    let devices = device::find_devices(&hidapi, &config.keyboard);
+    if cli.list_devices {
+        for device in &devices {
+            println!("{}", device::describe(device));
+        }
+        return Ok(());
+    }
+    let keyboards = if !config.keyboards.is_empty() {
+        config.keyboards.clone()
+    } else if config.all_keyboards {
+        devices
+            .iter()
+            .map(|info| KeyboardConfig {
+                name: Some(device::short_name(info)),
+                filter: config.keyboard.pinned_to(info),
+                rate: config.rate,
+                control_timeout_ms: config.control_timeout_ms,
+                ..KeyboardConfig::default()
+            })
+            .collect()
+    } else if devices.is_empty() {
+        info!("No keyboard found yet, waiting for it to be plugged in");
+        vec![KeyboardConfig {
+            filter: config.keyboard.clone(),
+            rate: config.rate,
+            control_timeout_ms: config.control_timeout_ms,
+            ..KeyboardConfig::default()
+        }]
+    } else if cli.headless && devices.len() > 1 {
+        // There is nobody to ask, and the default filter matches any VIA keyboard.
+        for device in &devices {
+            info!("Matching device {}", device::describe(device));
+        }
+        bail!(
+            "{} keyboards match, pass --all-keyboards to drive them all or narrow the filter",
+            devices.len()
+        );
+    } else {
+        let device_info = device::select_device(&devices)?;
+        info!("Using device {}", device::describe(device_info));
+        vec![KeyboardConfig {
+            name: Some(device::short_name(device_info)),
+            filter: config.keyboard.pinned_to(device_info),
+            rate: config.rate,
+            control_timeout_ms: config.control_timeout_ms,
+            ..KeyboardConfig::default()
+        }]
+    };
+    if keyboards.is_empty() {
+        bail!("Cannot find keyboard device");
+    }
 
     let (tx, rx): (Sender<ThreadCommand>, Receiver<ThreadCommand>) = mpsc::channel();
+    let control = tx.clone();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let handler_shutdown = shutdown.clone();
+    ctrlc::set_handler(move || handler_shutdown.store(true, Ordering::Release))
+        .context("Cannot install signal handler")?;
 
-    let device = get_default_audio_output_device().unwrap();
-    let _stream = capture_device_ouput(&device, processor.clone(), tx).unwrap();
+    let pipeline = Pipeline::spawn(Analyzer::new(&config.analysis), Analyzer::snapshot, tx);
+    let snapshot = pipeline.snapshot();
+    let mut capture =
+        AudioCapture::new(pipeline, config.audio.clone()).with_downmix(config.analysis.downmix);
+    match (&config.playback.file, config.generator.signal) {
+        (Some(path), _) => {
+            let source = FileSource::open(path)?;
+            capture.play(source.name(), Box::new(source), &config.playback)?;
+        }
+        (None, Some(signal)) => {
+            let source = Generator::new(signal, &config.generator);
+            capture.play(source.name(), Box::new(source), &config.playback)?;
+        }
+        (None, None) => {
+            // A failed start keeps retrying, e.g. until a daemon's audio server is up.
+            let started = capture.switch(config.audio.clone());
+            if let Err(err) = &started {
+                warn!("Cannot capture audio yet: {:#}", err);
+            }
+            if started.is_ok() && cli.has_audio_choice() {
+                // The resolved name, a substring or list position may match another device later.
+                let audio = AudioConfig {
+                    device: config
+                        .audio
+                        .device
+                        .as_ref()
+                        .and(capture.device_name().map(str::to_owned)),
+                    ..config.audio.clone()
+                };
+                if let Err(err) = Config::remember_audio(&audio) {
+                    warn!("Cannot remember the audio device: {:#}", err);
+                }
+            }
+        }
+    }
 
-    let protocol = Protocol::default();
+    let mut senders = Vec::new();
+    let mut connection_states = Vec::new();
+    let mut handles = Vec::new();
+    for (index, keyboard) in keyboards.into_iter().enumerate() {
+        let (keyboard_tx, keyboard_rx) = mpsc::channel();
+        let mut supervisor = Supervisor::new();
+        supervisor.output = keyboard.output();
+        supervisor.output.idle = config.idle;
+        let name = keyboard
+            .name
+            .unwrap_or_else(|| format!("Keyboard {}", index + 1));
+        connection_states.push((name.clone(), supervisor.state(), supervisor.stats()));
+        senders.push(keyboard_tx);
 
-    process_handshake(&hid_device, &protocol)?;
+        let snapshot_hid = snapshot.clone();
+        handles.push((
+            name,
+            thread::spawn(move || -> Result<()> {
+                supervisor.run_hid(keyboard.filter, snapshot_hid, keyboard_rx)
+            }),
+        ));
+    }
+    handles.push((
+        "Keyboard fan-out".to_owned(),
+        thread::spawn(move || {
+            fan_out(rx, senders);
+            Ok(())
+        }),
+    ));
 
-    let processor_hid = processor.clone();
-    let raw_hid_handle = std::thread::spawn(move || -> Result<()> {
-        hid_thread(&hid_device, &protocol, processor_hid, rx)
-    });
-    let mut terminal = setup_terminal().context("setup failed")?;
-    run(&mut terminal, processor.clone()).context("app loop failed")?;
-    restore_terminal(&mut terminal).context("restore terminal failed")?;
+    let result = if cli.headless {
+        run_headless(&mut capture, &connection_states, &shutdown);
+        Ok(())
+    } else {
+        // Log lines on stderr would tear up the terminal UI.
+        let level = log::max_level();
+        if cli.log_file.is_none() {
+            log::set_max_level(LevelFilter::Off);
+        }
+        let result = run_terminal(&mut capture, &connection_states, &shutdown);
+        log::set_max_level(level);
+        result
+    };
 
-    raw_hid_handle.join().unwrap()?;
-    Ok(())
+    info!("Shutting down");
+    capture.stop();
+    let _ = control.send(ThreadCommand::Stop);
+    drop(control);
+    // Without the pipeline the channel closes, so even a keyboard that missed the stop ends.
+    drop(capture);
+    join_with_timeout(handles, SHUTDOWN_TIMEOUT);
+    result
 }
 
-fn process_handshake(hid_device: &hidapi::HidDevice, protocol: &Protocol) -> Result<()> {
-    let handshake_command = Command::Handshake { status: 0x7F };
-    println!("Handshaking with keyboard...");
-    hid_device.write(&protocol.prepare_command(&handshake_command))?;
-    let mut hid_buffer = [0; PAGE_SIZE];
-    loop {
-        let bytes = hid_device.read(&mut hid_buffer)?;
-        println!("Response: {:?}", hid_buffer);
-        let command = protocol.to_command(&hid_buffer[0..bytes])?;
-        if let Command::Handshake { status } = command {
-            if status == 0x80 {
-                let handshake_command = Command::Handshake { status: 0x81 };
-                println!("Received correct status. Sending confirmation...");
-                hid_device.write(&protocol.prepare_command(&handshake_command))?;
-                return Ok(());
-            } else {
-                println!("Received wrong value. Re-Handshaking with keyboard...");
-                let handshake_command = Command::Handshake { status: 0x7F };
-                hid_device.write(&protocol.prepare_command(&handshake_command))?;
-            }
+// Threads stuck on a device are left behind, the process exit ends them.
+fn join_with_timeout(handles: Vec<(String, JoinHandle<Result<()>>)>, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    for (name, handle) in handles {
+        while !handle.is_finished() && Instant::now() < deadline {
+            thread::sleep(SHUTDOWN_POLL);
+        }
+        if !handle.is_finished() {
+            warn!("{}: did not stop within {:?}", name, timeout);
+            continue;
+        }
+        match handle.join() {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => warn!("{}: {:#}", name, err),
+            Err(_) => warn!("{}: thread panicked", name),
         }
     }
 }
 
-fn hid_thread(
-    hid_device: &hidapi::HidDevice,
-    protocol: &Protocol,
-    processor: Arc<Mutex<RmsProcessor>>,
-    rx: Receiver<ThreadCommand>,
-) -> Result<()> {
-    loop {
-        let command = rx.recv().unwrap();
-        match command {
-            ThreadCommand::ProcessorComplete => {
-                let rms = { processor.lock().unwrap().get_rms_u8() };
-                let command = Command::RMS {
-                    left: rms.0,
-                    right: rms.1,
-                };
-                hid_device.write(&protocol.prepare_command(&command))?;
+fn setup_logging(path: Option<&Path>) -> Result<()> {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(path) = path {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Cannot open log file {}", path.display()))?;
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    builder.try_init().context("Cannot set up logging")
+}
+
+fn run_headless(
+    capture: &mut AudioCapture<Snapshot>,
+    connection_states: &[(String, SharedConnectionState, SharedSendStats)],
+    shutdown: &AtomicBool,
+) {
+    let mut audio_status = String::new();
+    let mut keyboard_statuses = vec![String::new(); connection_states.len()];
+    let mut last_default_check = Instant::now();
+    while !shutdown.load(Ordering::Acquire) {
+        capture.poll();
+        if last_default_check.elapsed() >= DEFAULT_DEVICE_CHECK {
+            last_default_check = Instant::now();
+            if let Err(err) = capture.follow_default() {
+                warn!("Cannot follow the default audio device: {}", err);
+            }
+        }
+
+        // The retry countdown is left out so a pending retry is logged once.
+        let status = match capture.status() {
+            CaptureStatus::Running => {
+                format!("capturing {}", capture.device_name().unwrap_or("none"))
             }
+            CaptureStatus::Recovering {
+                reason, attempt, ..
+            } => format!("{}, retry {}", reason, attempt),
+            status => status.to_string(),
         };
+        if status != audio_status {
+            match capture.status() {
+                CaptureStatus::Running | CaptureStatus::Stopped => info!("Audio: {}", status),
+                _ => warn!("Audio: {}", status),
+            }
+            audio_status = status;
+        }
+        for ((name, state, _), last) in connection_states.iter().zip(&mut keyboard_statuses) {
+            let state = state.lock().unwrap();
+            let status = state.to_string();
+            if status != *last {
+                match *state {
+                    ConnectionState::Disconnected(_) => warn!("{}: {}", name, status),
+                    _ => info!("{}: {}", name, status),
+                }
+                *last = status;
+            }
+        }
+        thread::sleep(HEADLESS_POLL);
     }
 }
 
+// Errors are returned only after the terminal is restored, the keyboards still need a shutdown.
+fn run_terminal(
+    capture: &mut AudioCapture<Snapshot>,
+    connection_states: &[(String, SharedConnectionState, SharedSendStats)],
+    shutdown: &AtomicBool,
+) -> Result<()> {
+    let mut terminal = match setup_terminal() {
+        Ok(terminal) => terminal,
+        Err(err) => {
+            let _ = disable_raw_mode();
+            return Err(err.context("setup failed"));
+        }
+    };
+    let result =
+        run(&mut terminal, capture, connection_states, shutdown).context("app loop failed");
+    let restored = restore_terminal(&mut terminal).context("restore terminal failed");
+    result.and(restored)
+}
+
 fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
     let mut stdout = io::stdout();
     enable_raw_mode().context("failed to enable raw mode")?;
@@ -135,30 +326,155 @@ fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result
 
 fn run(
     terminal: &mut Terminal<CrosstermBackend<Stdout>>,
-    p: Arc<Mutex<RmsProcessor>>,
+    capture: &mut AudioCapture<Snapshot>,
+    connection_states: &[(String, SharedConnectionState, SharedSendStats)],
+    shutdown: &AtomicBool,
 ) -> Result<()> {
+    let snapshot = capture.pipeline().snapshot();
     let mut layout = visualizer::Layout::default();
     let mut vu_emulator = VUMeterEmulator::default();
-    loop {
+    let mut picker: Option<DevicePicker> = None;
+    let mut audio_error: Option<String> = None;
+    let mut last_default_check = Instant::now();
+    while !shutdown.load(Ordering::Acquire) {
         terminal.draw(|f| {
-            let rms = { p.lock().unwrap().get_rms::<f32>() };
-            vu_emulator.process(rms, &mut layout.colors);
+            let analysis = snapshot.load();
+            vu_emulator.process(analysis.level, &mut layout.colors);
             let widget = LayoutWidget { layout: &layout };
             f.render_widget(widget, f.size());
+            let meters = ChannelMetersWidget {
+                levels: &analysis.channel_levels,
+            };
+            f.render_widget(meters, status_area(f.size(), 0));
+            let source = match capture.playback() {
+                Some(_) => "playback".to_owned(),
+                None => capture.config().source.to_string(),
+            };
+            let audio_status = match (&audio_error, capture.status()) {
+                (Some(err), _) => format!("Audio: {}", err),
+                (None, CaptureStatus::Running) => format!(
+                    "Audio {}: {} (d: change device)",
+                    source,
+                    capture.device_name().unwrap_or("none")
+                ),
+                (None, status) => format!("Audio {}: {}", source, status),
+            };
+            f.render_widget(Line::from(audio_status), status_area(f.size(), 1));
+            for (index, (name, state, stats)) in connection_states.iter().enumerate() {
+                let state = state.lock().unwrap();
+                let status = match *state {
+                    ConnectionState::Connected { .. } => {
+                        format!("{}: {} ({})", name, state, stats.lock().unwrap())
+                    }
+                    _ => format!("{}: {}", name, state),
+                };
+                f.render_widget(Line::from(status), status_area(f.size(), index as u16 + 2));
+            }
+            if let Some(picker) = &picker {
+                let widget = DevicePickerWidget {
+                    picker,
+                    current: capture.device_name(),
+                };
+                f.render_widget(widget, picker_area(f.size(), picker.devices.len()));
+            }
         })?;
 
-        if should_quit()? {
+        let key = read_key()?;
+        // Raw mode turns Ctrl+C into a key press instead of a signal.
+        if key.is_some_and(|key| {
+            key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)
+        }) {
             break;
         }
+        match (&mut picker, key.map(|key| key.code)) {
+            (None, Some(KeyCode::Char('q'))) => break,
+            (None, Some(KeyCode::Char('d'))) => {
+                // Failing to list devices leaves the current capture running.
+                match device_picker(capture.config(), capture.config().source) {
+                    Ok(p) => picker = Some(p),
+                    Err(err) => audio_error = Some(err.to_string()),
+                }
+            }
+            (Some(_), Some(KeyCode::Esc)) => picker = None,
+            (Some(p), Some(KeyCode::Up)) => p.previous(),
+            (Some(p), Some(KeyCode::Down)) => p.next(),
+            (Some(p), Some(KeyCode::Tab)) => {
+                let source = match p.source {
+                    AudioSource::Output => AudioSource::Input,
+                    AudioSource::Input => AudioSource::Output,
+                };
+                match device_picker(capture.config(), source) {
+                    Ok(next) => *p = next,
+                    Err(err) => audio_error = Some(err.to_string()),
+                }
+            }
+            (Some(p), Some(KeyCode::Enter)) => {
+                let audio = AudioConfig {
+                    host: capture.config().host.clone(),
+                    device: p.choice().map(str::to_owned),
+                    source: p.source,
+                };
+                picker = None;
+                // A failed switch keeps retrying and shows up in the capture status.
+                audio_error = match capture.switch(audio.clone()) {
+                    Ok(()) => Config::remember_audio(&audio)
+                        .err()
+                        .map(|err| err.to_string()),
+                    Err(_) => None,
+                };
+            }
+            _ => (),
+        }
+
+        capture.poll();
+        if last_default_check.elapsed() >= DEFAULT_DEVICE_CHECK {
+            last_default_check = Instant::now();
+            // Errors from the picker or from remembering stay until something replaces them.
+            match capture.follow_default() {
+                Ok(true) => audio_error = None,
+                Ok(false) => (),
+                Err(err) => audio_error = Some(err.to_string()),
+            }
+        }
     }
     Ok(())
 }
 
-fn should_quit() -> Result<bool> {
+fn device_picker(audio: &AudioConfig, source: AudioSource) -> Result<DevicePicker> {
+    let host = find_host(audio.host.as_deref())?;
+    Ok(DevicePicker::new(
+        source,
+        get_audio_device_names(&host, source)?,
+    ))
+}
+
+fn picker_area(area: Rect, devices: usize) -> Rect {
+    let width = area.width.min(72);
+    let height = area.height.min(devices as u16 + 3);
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    }
+}
+
+fn status_area(area: Rect, line: u16) -> Rect {
+    let y = area.y + 7 + line;
+    Rect {
+        y: y.min(area.bottom().saturating_sub(1)),
+        height: 1.min(area.height),
+        ..area
+    }
+}
+
+fn read_key() -> Result<Option<KeyEvent>> {
     if event::poll(Duration::from_millis(16)).context("event poll failed")? {
         if let Event::Key(key) = event::read().context("event read failed")? {
-            return Ok(KeyCode::Char('q') == key.code);
+            if key.kind == KeyEventKind::Press {
+                return Ok(Some(key));
+            }
         }
     }
-    Ok(false)
+    Ok(None)
 }
@@ -0,0 +1,218 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use cpal::{Sample, StreamConfig, StreamError};
+use dasp_sample::ToSample;
+use ringbuf::{
+    traits::{Consumer, Observer, Producer, Split},
+    HeapCons, HeapProd, HeapRb,
+};
+
+use crate::{audio_capture::Processor, protocol::ThreadCommand};
+
+// Half a second of audio can queue up before the callback starts dropping samples.
+const BUFFER_DURATION: f32 = 0.5;
+const WORKER_POLL: Duration = Duration::from_millis(50);
+
+pub type Shared<T> = Arc<ArcSwap<T>>;
+
+// Audio callback side of the pipeline, never blocks or allocates.
+pub struct AudioInput {
+    producer: HeapProd<f32>,
+    channels: usize,
+    errors: Sender<StreamError>,
+    worker: thread::Thread,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl AudioInput {
+    pub fn push<S>(&mut self, data: &[S])
+    where
+        S: Sample + ToSample<f32>,
+    {
+        // Only whole frames are queued so the worker never loses channel alignment.
+        let vacant = self.producer.vacant_len() / self.channels * self.channels;
+        let count = data.len().min(vacant);
+        self.producer
+            .push_iter(data[..count].iter().map(|s| s.to_sample::<f32>()));
+        if count < data.len() {
+            self.dropped
+                .fetch_add((data.len() - count) / self.channels, Ordering::Relaxed);
+        }
+        self.worker.unpark();
+    }
+
+    pub fn vacant_frames(&self) -> usize {
+        self.producer.vacant_len() / self.channels
+    }
+
+    pub fn error_handler(&self) -> impl FnMut(StreamError) + Send + 'static {
+        let errors = self.errors.clone();
+        let worker = self.worker.clone();
+        move |err| {
+            let _ = errors.send(err);
+            worker.unpark();
+        }
+    }
+}
+
+struct Source {
+    consumer: HeapCons<f32>,
+    config: StreamConfig,
+}
+
+pub struct Pipeline<T> {
+    snapshot: Shared<T>,
+    running: Arc<AtomicBool>,
+    dropped: Arc<AtomicUsize>,
+    sources: Sender<Source>,
+    errors: Sender<StreamError>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl<T> Pipeline<T>
+where
+    T: Send + Sync + 'static,
+{
+    pub fn spawn<P, F>(processor: P, mut publish: F, tx: Sender<ThreadCommand>) -> Self
+    where
+        P: Processor + 'static,
+        F: FnMut(&P) -> T + Send + 'static,
+    {
+        let (sources, source_rx) = mpsc::channel();
+        let (errors, error_rx) = mpsc::channel();
+        let snapshot = Arc::new(ArcSwap::from_pointee(publish(&processor)));
+        let running = Arc::new(AtomicBool::new(true));
+        let timeout = processor.timeout();
+
+        let worker = Worker {
+            processor,
+            timeout,
+            source: None,
+            sources: source_rx,
+            errors: error_rx,
+            tx,
+        };
+        let worker_snapshot = snapshot.clone();
+        let worker_running = running.clone();
+        let handle = thread::spawn(move || worker.run(publish, &worker_snapshot, &worker_running));
+
+        Self {
+            snapshot,
+            running,
+            dropped: Arc::new(AtomicUsize::new(0)),
+            sources,
+            errors,
+            worker: Some(handle),
+        }
+    }
+
+    // Every call replaces the input the worker reads from, e.g. after switching devices.
+    pub fn input(&self, config: &StreamConfig) -> AudioInput {
+        let channels = (config.channels as usize).max(1);
+        let capacity = (config.sample_rate.0 as f32 * BUFFER_DURATION) as usize * channels;
+        let (producer, consumer) = HeapRb::<f32>::new(capacity.max(channels)).split();
+        let worker = self.worker.as_ref().unwrap().thread().clone();
+        let _ = self.sources.send(Source {
+            consumer,
+            config: config.clone(),
+        });
+        worker.unpark();
+        AudioInput {
+            producer,
+            channels,
+            errors: self.errors.clone(),
+            worker,
+            dropped: self.dropped.clone(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Shared<T> {
+        self.snapshot.clone()
+    }
+
+    pub fn dropped_frames(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Drop for Pipeline<T> {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(worker) = self.worker.take() {
+            worker.thread().unpark();
+            let _ = worker.join();
+        }
+    }
+}
+
+struct Worker<P> {
+    processor: P,
+    timeout: Option<Duration>,
+    source: Option<Source>,
+    sources: Receiver<Source>,
+    errors: Receiver<StreamError>,
+    tx: Sender<ThreadCommand>,
+}
+
+impl<P> Worker<P>
+where
+    P: Processor,
+{
+    fn run<T, F>(mut self, mut publish: F, snapshot: &ArcSwap<T>, running: &AtomicBool)
+    where
+        F: FnMut(&P) -> T,
+    {
+        let mut buffer = Vec::new();
+        let mut last_data = Instant::now();
+        let mut last_tick = Instant::now();
+        while running.load(Ordering::Acquire) {
+            if let Some(source) = self.sources.try_iter().last() {
+                buffer.resize(source.consumer.capacity().get(), 0f32);
+                self.source = Some(source);
+            }
+            for err in self.errors.try_iter() {
+                self.processor.process_error(err);
+            }
+            let available = self.source.as_ref().map_or(0, |source| {
+                let channels = (source.config.channels as usize).max(1);
+                source.consumer.occupied_len() / channels * channels
+            });
+            if available > 0 {
+                let source = self.source.as_mut().unwrap();
+                let count = source.consumer.pop_slice(&mut buffer[..available]);
+                self.processor.process(&buffer[..count], &source.config);
+                last_data = Instant::now();
+            } else {
+                // Without any buffers for too long the processor is told about the gap.
+                match self.timeout {
+                    Some(timeout)
+                        if last_data.elapsed() >= timeout && last_tick.elapsed() >= WORKER_POLL =>
+                    {
+                        self.processor.process_timeout(last_tick.elapsed());
+                    }
+                    _ => {
+                        thread::park_timeout(WORKER_POLL);
+                        continue;
+                    }
+                }
+            }
+            last_tick = Instant::now();
+            snapshot.store(Arc::new(publish(&self.processor)));
+
+            // Keyboards may come and go, a closed channel is not an error here.
+            for event in std::iter::from_fn(|| self.processor.take_event()) {
+                let _ = self.tx.send(event);
+            }
+            let _ = self.tx.send(ThreadCommand::ProcessorComplete);
+        }
+    }
+}
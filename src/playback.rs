@@ -0,0 +1,160 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use cpal::StreamConfig;
+use serde::Deserialize;
+
+use crate::pipeline::{AudioInput, Pipeline};
+
+const PLAYBACK_POLL: Duration = Duration::from_millis(5);
+// Samples are handed to the pipeline in 10 ms blocks, like a typical device callback.
+const BLOCKS_PER_SECOND: u32 = 100;
+
+pub trait SampleSource: Send {
+    fn config(&self) -> &StreamConfig;
+    // Appends the next interleaved samples, returns false once the source is exhausted.
+    fn read(&mut self, buffer: &mut Vec<f32>) -> Result<bool>;
+    fn rewind(&mut self) -> Result<()>;
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PlaybackConfig {
+    pub file: Option<PathBuf>,
+    pub speed: f32,
+    pub repeat: bool,
+}
+
+impl Default for PlaybackConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            speed: 1.0,
+            repeat: false,
+        }
+    }
+}
+
+pub struct Playback {
+    name: String,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl Playback {
+    pub fn spawn<T>(
+        name: String,
+        source: Box<dyn SampleSource>,
+        config: &PlaybackConfig,
+        pipeline: &Pipeline<T>,
+    ) -> Self
+    where
+        T: Send + Sync + 'static,
+    {
+        let input = pipeline.input(source.config());
+        let running = Arc::new(AtomicBool::new(true));
+        let player = Player {
+            source,
+            input,
+            speed: config.speed,
+            repeat: config.repeat,
+        };
+        let player_running = running.clone();
+        let handle = thread::spawn(move || player.run(&player_running));
+        Self {
+            name,
+            running,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Returns the outcome once, after the source has played to its end or failed.
+    pub fn finished(&mut self) -> Option<Result<()>> {
+        if !self.handle.as_ref()?.is_finished() {
+            return None;
+        }
+        let handle = self.handle.take()?;
+        Some(
+            handle
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Playback thread panicked"))),
+        )
+    }
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct Player {
+    source: Box<dyn SampleSource>,
+    input: AudioInput,
+    speed: f32,
+    repeat: bool,
+}
+
+impl Player {
+    fn run(mut self, running: &AtomicBool) -> Result<()> {
+        let config = self.source.config().clone();
+        let channels = (config.channels as usize).max(1);
+        let block = (config.sample_rate.0 / BLOCKS_PER_SECOND).max(1) as usize * channels;
+        let frame_rate = config.sample_rate.0 as f64 * self.speed as f64;
+
+        let started = Instant::now();
+        let mut played = 0u64;
+        let mut since_rewind = 0u64;
+        let mut buffer = Vec::new();
+        let mut offset = 0;
+        while running.load(Ordering::Acquire) {
+            if offset == buffer.len() {
+                buffer.clear();
+                offset = 0;
+                if !self.source.read(&mut buffer)? {
+                    // An empty source would otherwise spin forever.
+                    if !self.repeat || since_rewind == 0 {
+                        return Ok(());
+                    }
+                    self.source.rewind()?;
+                    since_rewind = 0;
+                }
+                continue;
+            }
+
+            // Files are not real time, so wait for room instead of dropping frames.
+            let count = (buffer.len() - offset)
+                .min(block)
+                .min(self.input.vacant_frames() * channels);
+            if count == 0 {
+                thread::sleep(PLAYBACK_POLL);
+                continue;
+            }
+            self.input.push(&buffer[offset..offset + count]);
+            offset += count;
+            played += (count / channels) as u64;
+            since_rewind += (count / channels) as u64;
+
+            if frame_rate > 0.0 {
+                let due = started + Duration::from_secs_f64(played as f64 / frame_rate);
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,119 @@
 use std::fmt::Display;
 
+use crate::beat::Beat;
+
+#[derive(Clone, Copy, Debug)]
 pub enum ThreadCommand {
     ProcessorComplete,
+    Beat(Beat),
+    Stop,
 }
 
 pub const PAGE_SIZE: usize = 33;
+pub const PROTOCOL_VERSION: u8 = 2;
+pub const LEGACY_PROTOCOL_VERSION: u8 = 1;
+// 4 header bytes, then command, length, sequence and packet count, the last report byte is unused.
+pub const CUSTOM_DATA_CHUNK: usize = 24;
+// 4 header bytes, then command and band count.
+pub const MAX_BANDS: usize = 27;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(pub u8);
+
+impl Capabilities {
+    pub const RMS: u8 = 0x01;
+    pub const CUSTOM_DATA: u8 = 0x02;
+    pub const BANDS: u8 = 0x04;
+    pub const BEAT: u8 = 0x08;
+    pub const CONTROL: u8 = 0x10;
+    pub const EVENTS: u8 = 0x20;
+
+    pub fn host() -> Self {
+        Self(
+            Self::RMS | Self::CUSTOM_DATA | Self::BANDS | Self::BEAT | Self::CONTROL | Self::EVENTS,
+        )
+    }
+
+    pub fn legacy() -> Self {
+        Self(Self::RMS)
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.0 & flag == flag
+    }
+
+    pub fn supports(&self, command: &Command) -> bool {
+        match command {
+            Command::Handshake { .. } | Command::Version { .. } => true,
+            Command::RMS { .. } => self.has(Self::RMS),
+            Command::CustomData { .. } => self.has(Self::CUSTOM_DATA),
+            Command::Bands { .. } => self.has(Self::BANDS),
+            Command::Beat { .. } => self.has(Self::BEAT),
+            Command::Take { .. } | Command::Heartbeat | Command::Release => self.has(Self::CONTROL),
+            Command::CycleMode | Command::Sensitivity | Command::Pause => self.has(Self::EVENTS),
+        }
+    }
+}
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Command {
-    Handshake { status: u8 },
-    RMS { left: u8, right: u8 },
-    CustomData { length: u8 },
+    Handshake {
+        status: u8,
+    },
+    RMS {
+        left: u8,
+        right: u8,
+    },
+    CustomData {
+        length: u8,
+        sequence: u8,
+        count: u8,
+        data: [u8; CUSTOM_DATA_CHUNK],
+    },
+    Version {
+        version: u8,
+        capabilities: u8,
+    },
+    Bands {
+        count: u8,
+        values: [u8; MAX_BANDS],
+    },
+    Beat {
+        strength: u8,
+        bpm: u8,
+    },
+    // The host is done, the firmware goes back to its own RGB mode.
+    Release,
+    // The host drives the lighting until no report arrived for `timeout` tenths of a second.
+    Take {
+        timeout: u8,
+    },
+    Heartbeat,
+    // Sent by the keyboard: switch to the next output mode.
+    CycleMode,
+    // Sent by the keyboard: step through the level gains.
+    Sensitivity,
+    // Sent by the keyboard: toggle between visualizing and the firmware's own lighting.
+    Pause,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CommandParseError {
     CommandByteError,
     HandshakeStatusError,
     RMSValueError(u8),
     UndefinedCommand(u8),
     CorruptedHeader,
+    ReportLengthError(usize),
+    ReservedByteError(usize),
     CustomDataLengthError,
+    CustomDataSequenceError,
+    CustomDataPayloadError(u8),
+    VersionError,
+    BandCountError,
+    BandValueError(u8),
+    BeatValueError,
+    ControlTimeoutError,
 }
 
 impl Display for CommandParseError {
@@ -36,22 +130,134 @@ impl Display for CommandParseError {
             CommandParseError::CorruptedHeader => {
                 write!(f, "Corrupted header")
             }
+            CommandParseError::ReportLengthError(length) => {
+                write!(f, "Report of {} bytes does not fit the protocol", length)
+            }
+            CommandParseError::ReservedByteError(offset) => {
+                write!(f, "Reserved byte {} is not zero", offset)
+            }
             CommandParseError::CustomDataLengthError => {
                 write!(f, "Cannot get custom data chunk length")
             }
+            CommandParseError::CustomDataSequenceError => {
+                write!(f, "Cannot get custom data sequence number")
+            }
+            CommandParseError::CustomDataPayloadError(length) => {
+                write!(f, "Cannot get {} bytes of custom data payload", length)
+            }
+            CommandParseError::VersionError => {
+                write!(f, "Cannot get protocol version or capabilities")
+            }
+            CommandParseError::BandCountError => write!(f, "Cannot get band count"),
+            CommandParseError::BandValueError(band) => {
+                write!(f, "Cannot get value byte for band {}", band)
+            }
+            CommandParseError::BeatValueError => write!(f, "Cannot get beat strength or tempo"),
+            CommandParseError::ControlTimeoutError => write!(f, "Cannot get control timeout"),
         }
     }
 }
 
 impl std::error::Error for CommandParseError {}
 
-impl Command {
-    pub fn to_data(&self) -> Vec<u8> {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandEncodeError {
+    CustomDataLengthError(u8),
+    CustomDataSequenceError { sequence: u8, count: u8 },
+    CustomDataPaddingError,
+    BandCountError(u8),
+    BandPaddingError,
+}
+
+impl Display for CommandEncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            CommandEncodeError::CustomDataLengthError(length) => write!(
+                f,
+                "Custom data chunk of {} bytes exceeds {} bytes",
+                length, CUSTOM_DATA_CHUNK
+            ),
+            CommandEncodeError::CustomDataSequenceError { sequence, count } => write!(
+                f,
+                "Custom data packet {} is out of a {} packet sequence",
+                sequence, count
+            ),
+            CommandEncodeError::CustomDataPaddingError => {
+                write!(f, "Custom data bytes past the chunk length are not zero")
+            }
+            CommandEncodeError::BandCountError(count) => {
+                write!(f, "{} bands exceed {} bands", count, MAX_BANDS)
+            }
+            CommandEncodeError::BandPaddingError => {
+                write!(f, "Band values past the band count are not zero")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandEncodeError {}
+
+impl Command {
+    pub fn is_event(&self) -> bool {
+        matches!(
+            self,
+            Command::CycleMode | Command::Sensitivity | Command::Pause
+        )
+    }
+
+    // Values the decoder would reject, or could not restore, are refused instead of cut off.
+    pub fn to_data(&self) -> Result<Vec<u8>, CommandEncodeError> {
+        let data = match self {
             Command::Handshake { status } => vec![self.into(), *status],
             Command::RMS { left, right } => vec![self.into(), *left, *right],
-            Command::CustomData { length } => vec![self.into(), *length],
-        }
+            Command::CustomData {
+                length,
+                sequence,
+                count,
+                data,
+            } => {
+                if *length as usize > CUSTOM_DATA_CHUNK {
+                    return Err(CommandEncodeError::CustomDataLengthError(*length));
+                }
+                if sequence >= count {
+                    return Err(CommandEncodeError::CustomDataSequenceError {
+                        sequence: *sequence,
+                        count: *count,
+                    });
+                }
+                let (payload, padding) = data.split_at(*length as usize);
+                if padding.iter().any(|byte| *byte != 0) {
+                    return Err(CommandEncodeError::CustomDataPaddingError);
+                }
+                let mut result = vec![self.into(), *length, *sequence, *count];
+                result.extend_from_slice(payload);
+                result
+            }
+            Command::Version {
+                version,
+                capabilities,
+            } => vec![self.into(), *version, *capabilities],
+            Command::Bands { count, values } => {
+                if *count as usize > MAX_BANDS {
+                    return Err(CommandEncodeError::BandCountError(*count));
+                }
+                let (bands, padding) = values.split_at(*count as usize);
+                if padding.iter().any(|value| *value != 0) {
+                    return Err(CommandEncodeError::BandPaddingError);
+                }
+                let mut result = vec![self.into(), *count];
+                result.extend_from_slice(bands);
+                result
+            }
+            Command::Beat { strength, bpm } => vec![self.into(), *strength, *bpm],
+            Command::Release
+            | Command::Heartbeat
+            | Command::CycleMode
+            | Command::Sensitivity
+            | Command::Pause => vec![self.into()],
+            Command::Take { timeout } => vec![self.into(), *timeout],
+        };
+        Ok(data)
     }
 }
 
@@ -61,6 +267,53 @@ impl From<&Command> for u8 {
             Command::Handshake { .. } => 0x01,
             Command::RMS { .. } => 0x02,
             Command::CustomData { .. } => 0x03,
+            Command::Version { .. } => 0x04,
+            Command::Bands { .. } => 0x05,
+            Command::Beat { .. } => 0x06,
+            Command::Release => 0x07,
+            Command::Take { .. } => 0x08,
+            Command::Heartbeat => 0x09,
+            Command::CycleMode => 0x0A,
+            Command::Sensitivity => 0x0B,
+            Command::Pause => 0x0C,
+        }
+    }
+}
+
+// Reads the payload of one command, every byte after it is reserved and must be zero.
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn byte(&mut self, err: CommandParseError) -> Result<u8, CommandParseError> {
+        let byte = *self.data.get(self.offset).ok_or(err)?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn bytes(
+        &mut self,
+        length: usize,
+        err: CommandParseError,
+    ) -> Result<&'a [u8], CommandParseError> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + length)
+            .ok_or(err)?;
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    fn finish(self, command: Command) -> Result<Command, CommandParseError> {
+        match self.data[self.offset..].iter().position(|byte| *byte != 0) {
+            Some(position) => Err(CommandParseError::ReservedByteError(self.offset + position)),
+            None => Ok(command),
         }
     }
 }
@@ -69,20 +322,102 @@ impl TryFrom<&[u8]> for Command {
     type Error = CommandParseError;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let command_index = value.first().ok_or(Self::Error::CommandByteError)?;
-        match command_index {
-            0x01 => Ok(Command::Handshake {
-                status: *value.get(1).ok_or(Self::Error::HandshakeStatusError)?,
-            }),
-            0x02 => Ok(Command::RMS {
-                left: *value.get(1).ok_or(Self::Error::RMSValueError(0))?,
-                right: *value.get(2).ok_or(Self::Error::RMSValueError(1))?,
-            }),
-            0x03 => Ok(Command::CustomData {
-                length: *value.get(1).ok_or(Self::Error::CustomDataLengthError)?,
-            }),
-            _ => Err(CommandParseError::UndefinedCommand(*command_index)),
-        }
+        let mut reader = Reader::new(value);
+        let command_index = reader.byte(Self::Error::CommandByteError)?;
+        let command = match command_index {
+            0x01 => Command::Handshake {
+                status: reader.byte(Self::Error::HandshakeStatusError)?,
+            },
+            0x02 => Command::RMS {
+                left: reader.byte(Self::Error::RMSValueError(0))?,
+                right: reader.byte(Self::Error::RMSValueError(1))?,
+            },
+            0x03 => {
+                let length = reader.byte(Self::Error::CustomDataLengthError)?;
+                if length as usize > CUSTOM_DATA_CHUNK {
+                    return Err(Self::Error::CustomDataLengthError);
+                }
+                let sequence = reader.byte(Self::Error::CustomDataSequenceError)?;
+                let count = reader.byte(Self::Error::CustomDataSequenceError)?;
+                if sequence >= count {
+                    return Err(Self::Error::CustomDataSequenceError);
+                }
+                let payload =
+                    reader.bytes(length as usize, Self::Error::CustomDataPayloadError(length))?;
+                let mut data = [0; CUSTOM_DATA_CHUNK];
+                data[..payload.len()].copy_from_slice(payload);
+                Command::CustomData {
+                    length,
+                    sequence,
+                    count,
+                    data,
+                }
+            }
+            0x04 => Command::Version {
+                version: reader.byte(Self::Error::VersionError)?,
+                capabilities: reader.byte(Self::Error::VersionError)?,
+            },
+            0x05 => {
+                let count = reader.byte(Self::Error::BandCountError)?;
+                if count as usize > MAX_BANDS {
+                    return Err(Self::Error::BandCountError);
+                }
+                let mut values = [0; MAX_BANDS];
+                for (band, band_value) in values.iter_mut().enumerate().take(count as usize) {
+                    *band_value = reader.byte(Self::Error::BandValueError(band as u8))?;
+                }
+                Command::Bands { count, values }
+            }
+            0x06 => Command::Beat {
+                strength: reader.byte(Self::Error::BeatValueError)?,
+                bpm: reader.byte(Self::Error::BeatValueError)?,
+            },
+            0x07 => Command::Release,
+            0x08 => Command::Take {
+                timeout: reader.byte(Self::Error::ControlTimeoutError)?,
+            },
+            0x09 => Command::Heartbeat,
+            0x0A => Command::CycleMode,
+            0x0B => Command::Sensitivity,
+            0x0C => Command::Pause,
+            _ => return Err(CommandParseError::UndefinedCommand(command_index)),
+        };
+        reader.finish(command)
+    }
+}
+
+pub fn frame_commands(frame: &[u8]) -> Vec<Command> {
+    let chunks = frame.chunks(CUSTOM_DATA_CHUNK).take(u8::MAX as usize);
+    let count = chunks.len() as u8;
+    chunks
+        .enumerate()
+        .map(|(sequence, chunk)| {
+            let mut data = [0; CUSTOM_DATA_CHUNK];
+            data[..chunk.len()].copy_from_slice(chunk);
+            Command::CustomData {
+                length: chunk.len() as u8,
+                sequence: sequence as u8,
+                count,
+                data,
+            }
+        })
+        .collect()
+}
+
+pub fn bands_command(bands: &[u8]) -> Command {
+    let count = bands.len().min(MAX_BANDS);
+    let mut values = [0; MAX_BANDS];
+    values[..count].copy_from_slice(&bands[..count]);
+    Command::Bands {
+        count: count as u8,
+        values,
+    }
+}
+
+pub fn beat_command(beat: &Beat) -> Command {
+    Command::Beat {
+        strength: (beat.strength * 255f32) as u8,
+        bpm: beat.bpm.round().clamp(0f32, 255f32) as u8,
     }
 }
 
@@ -97,32 +432,32 @@ impl Protocol {
         [0, b'k', b'b', b'm']
     }
 
-    pub fn prepare_command(&self, command: &Command) -> [u8; PAGE_SIZE] {
+    pub fn prepare_command(
+        &self,
+        command: &Command,
+    ) -> Result<[u8; PAGE_SIZE], CommandEncodeError> {
         let mut data: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
         let header = self.header();
         let (header_chunk, data_chunk) = data.split_at_mut(header.len());
         header_chunk.copy_from_slice(&header);
-        let command_data = command.to_data();
+        let command_data = command.to_data()?;
         for (index, c) in command_data.iter().enumerate() {
             data_chunk[index] = *c;
         }
-        data
+        Ok(data)
     }
 
+    // Decodes an input report, which arrives without the leading report id.
     pub fn to_command(&self, data: &[u8]) -> Result<Command, CommandParseError> {
-        let header = &data[0..3];
-        let protocol_header = self.header();
-        let mut is_header_valid = true;
-        for (index, h) in header.iter().enumerate() {
-            if *h != protocol_header[index + 1] {
-                is_header_valid = false;
-            }
+        let header = &self.header()[1..];
+        if data.len() < header.len() || data.len() > PAGE_SIZE - 1 {
+            return Err(CommandParseError::ReportLengthError(data.len()));
         }
-        if !is_header_valid {
+        let (report_header, command) = data.split_at(header.len());
+        if report_header != header {
             return Err(CommandParseError::CorruptedHeader);
         }
-
-        Command::try_from(&data[3..])
+        Command::try_from(command)
     }
 }
 
@@ -0,0 +1,108 @@
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::protocol::Command;
+
+pub const DEFAULT_SEND_RATE: u32 = 60;
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SendStats {
+    pub rate: f32,
+    pub sent: u64,
+    pub skipped: u64,
+    pub dropped: u64,
+}
+
+impl Display for SendStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.0} Hz, {} unchanged, {} dropped",
+            self.rate, self.skipped, self.dropped
+        )
+    }
+}
+
+pub type SharedSendStats = Arc<Mutex<SendStats>>;
+
+pub struct Scheduler {
+    interval: Duration,
+    next_send: Instant,
+    pending: bool,
+    last_sent: Vec<Command>,
+    stats: SharedSendStats,
+    window_start: Instant,
+    window_sent: u32,
+}
+
+impl Scheduler {
+    pub fn new(rate: u32, stats: SharedSendStats) -> Self {
+        let interval = if rate == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(1) / rate
+        };
+        let now = Instant::now();
+        Self {
+            interval,
+            next_send: now,
+            pending: false,
+            last_sent: Vec::new(),
+            stats,
+            window_start: now,
+            window_sent: 0,
+        }
+    }
+
+    pub fn mark(&mut self) {
+        if self.pending {
+            self.stats.lock().unwrap().dropped += 1;
+        }
+        self.pending = true;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        if self.pending {
+            Some(self.next_send.saturating_duration_since(Instant::now()))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.pending && Instant::now() >= self.next_send
+    }
+
+    pub fn flush(&mut self) {
+        self.next_send = Instant::now();
+    }
+
+    pub fn take(&mut self, commands: &[Command]) -> bool {
+        let now = Instant::now();
+        self.pending = false;
+        self.next_send = (self.next_send + self.interval).max(now);
+        // A tick without commands sends nothing, but the next commands count as changed.
+        let changed = !commands.is_empty() && self.last_sent != commands;
+        let mut stats = self.stats.lock().unwrap();
+        if commands.is_empty() {
+            self.last_sent.clear();
+        } else if changed {
+            self.last_sent = commands.to_vec();
+            stats.sent += 1;
+            self.window_sent += 1;
+        } else {
+            stats.skipped += 1;
+        }
+        let elapsed = now - self.window_start;
+        if elapsed >= RATE_WINDOW {
+            stats.rate = self.window_sent as f32 / elapsed.as_secs_f32();
+            self.window_start = now;
+            self.window_sent = 0;
+        }
+        changed
+    }
+}
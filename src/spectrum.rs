@@ -0,0 +1,190 @@
+use std::{f32::consts::PI, sync::Arc, time::Duration};
+
+use cpal::{Sample, SizedSample, StreamConfig, StreamError};
+use dasp_sample::ToSample;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
+use crate::{
+    audio_capture::Processor,
+    bands::{self, quantize_db},
+};
+
+pub const DEFAULT_FFT_SIZE: usize = 2048;
+pub const DEFAULT_HOP: usize = 512;
+
+pub struct SpectrumProcessor {
+    fft_size: usize,
+    hop: usize,
+    band_count: usize,
+    min_frequency: f32,
+    max_frequency: f32,
+    db_floor: f32,
+    sample_rate: u32,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    window_gain: f32,
+    input: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    band_bins: Vec<(usize, usize)>,
+    spectrum_db: Vec<f32>,
+    bands_db: Vec<f32>,
+}
+
+impl SpectrumProcessor {
+    pub fn new(fft_size: usize, hop: usize, band_count: usize) -> Self {
+        let fft_size = fft_size.max(2);
+        let fft = FftPlanner::new().plan_fft_forward(fft_size);
+        let window: Vec<f32> = (0..fft_size)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / fft_size as f32).cos())
+            .collect();
+        let window_gain = window.iter().sum::<f32>() / 2.0;
+        Self {
+            fft_size,
+            hop: hop.clamp(1, fft_size),
+            band_count,
+            min_frequency: bands::DEFAULT_MIN_FREQUENCY,
+            max_frequency: bands::DEFAULT_MAX_FREQUENCY,
+            db_floor: bands::DEFAULT_DB_FLOOR,
+            sample_rate: 0,
+            scratch: vec![Complex::default(); fft.get_inplace_scratch_len()],
+            fft,
+            window,
+            window_gain,
+            input: Vec::with_capacity(fft_size * 2),
+            buffer: vec![Complex::default(); fft_size],
+            band_bins: Vec::new(),
+            spectrum_db: vec![bands::DEFAULT_DB_FLOOR; fft_size / 2 + 1],
+            bands_db: vec![bands::DEFAULT_DB_FLOOR; band_count],
+        }
+    }
+
+    pub fn with_frequency_range(mut self, min_frequency: f32, max_frequency: f32) -> Self {
+        self.min_frequency = min_frequency;
+        self.max_frequency = max_frequency;
+        self.sample_rate = 0;
+        self
+    }
+
+    pub fn with_db_floor(mut self, db_floor: f32) -> Self {
+        self.db_floor = db_floor;
+        self
+    }
+
+    pub fn fft_size(&self) -> usize {
+        self.fft_size
+    }
+
+    pub fn hop(&self) -> usize {
+        self.hop
+    }
+
+    pub fn bin_frequency(&self, bin: usize) -> f32 {
+        bin as f32 * self.sample_rate as f32 / self.fft_size as f32
+    }
+
+    fn rebuild_bands(&mut self, sample_rate: u32) {
+        let bin_width = sample_rate as f32 / self.fft_size as f32;
+        let max_frequency = self.max_frequency.min(sample_rate as f32 / 2.0);
+        let ratio = max_frequency / self.min_frequency;
+        let last_bin = self.fft_size / 2;
+        self.band_bins = (0..self.band_count)
+            .map(|band| {
+                let low = self.min_frequency * ratio.powf(band as f32 / self.band_count as f32);
+                let high =
+                    self.min_frequency * ratio.powf((band + 1) as f32 / self.band_count as f32);
+                let first = ((low / bin_width).ceil() as usize).min(last_bin);
+                let end = ((high / bin_width).ceil() as usize).min(last_bin + 1);
+                if first < end {
+                    (first, end)
+                } else {
+                    // Narrow low bands may fall between bins, use the nearest one.
+                    let nearest =
+                        (((low * high).sqrt() / bin_width).round() as usize).min(last_bin);
+                    (nearest, nearest + 1)
+                }
+            })
+            .collect();
+        self.sample_rate = sample_rate;
+    }
+
+    fn analyze(&mut self) {
+        for ((value, sample), weight) in self.buffer.iter_mut().zip(&self.input).zip(&self.window) {
+            *value = Complex::new(sample * weight, 0.0);
+        }
+        self.fft
+            .process_with_scratch(&mut self.buffer, &mut self.scratch);
+        for (db, value) in self.spectrum_db.iter_mut().zip(&self.buffer) {
+            let amplitude = value.norm() / self.window_gain;
+            *db = (20.0 * amplitude.max(f32::MIN_POSITIVE).log10()).max(self.db_floor);
+        }
+        for (db, (first, end)) in self.bands_db.iter_mut().zip(&self.band_bins) {
+            *db = self.spectrum_db[*first..*end]
+                .iter()
+                .copied()
+                .fold(self.db_floor, f32::max);
+        }
+    }
+
+    pub fn process_samples<S>(&mut self, data: &[S], channels: usize, sample_rate: u32)
+    where
+        S: Sample + ToSample<f32>,
+    {
+        if channels == 0 {
+            return;
+        }
+        if self.sample_rate != sample_rate {
+            self.rebuild_bands(sample_rate);
+        }
+        for frame in data.chunks_exact(channels) {
+            let mono = frame.iter().map(|s| s.to_sample::<f32>()).sum::<f32>() / channels as f32;
+            self.input.push(mono);
+            if self.input.len() == self.fft_size {
+                self.analyze();
+                self.input.drain(..self.hop);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.input.clear();
+        self.spectrum_db.fill(self.db_floor);
+        self.bands_db.fill(self.db_floor);
+    }
+
+    pub fn get_spectrum_db(&self) -> &[f32] {
+        &self.spectrum_db
+    }
+
+    pub fn get_bands_db(&self) -> &[f32] {
+        &self.bands_db
+    }
+
+    pub fn get_bands_u8(&self) -> Vec<u8> {
+        self.bands_db
+            .iter()
+            .map(|db| quantize_db(10f32.powf(db / 20.0), self.db_floor))
+            .collect()
+    }
+}
+
+impl Default for SpectrumProcessor {
+    fn default() -> Self {
+        Self::new(DEFAULT_FFT_SIZE, DEFAULT_HOP, bands::DEFAULT_BAND_COUNT)
+    }
+}
+
+impl Processor for SpectrumProcessor {
+    fn process<S>(&mut self, data: &[S], config: &StreamConfig)
+    where
+        S: SizedSample + ToSample<f32>,
+    {
+        self.process_samples(data, config.channels as usize, config.sample_rate.0);
+    }
+
+    fn process_error(&mut self, _err: StreamError) {}
+
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+}
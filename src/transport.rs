@@ -0,0 +1,20 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use hidapi::HidDevice;
+
+pub trait Transport: Send {
+    fn write_report(&self, data: &[u8]) -> Result<usize>;
+    fn read_report(&self, buf: &mut [u8], timeout: Option<Duration>) -> Result<usize>;
+}
+
+impl Transport for HidDevice {
+    fn write_report(&self, data: &[u8]) -> Result<usize> {
+        Ok(self.write(data)?)
+    }
+
+    fn read_report(&self, buf: &mut [u8], timeout: Option<Duration>) -> Result<usize> {
+        let timeout = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        Ok(self.read_timeout(buf, timeout)?)
+    }
+}
@@ -10,12 +10,14 @@
 use ratatui::{
     buffer::Buffer,
     layout::*,
-    style::{Color, Style},
+    style::{Color, Modifier, Style},
     text::Line,
-    widgets::Widget,
+    widgets::{Block, Borders, Clear, Widget},
 };
 use std::fmt::Display;
 
+use crate::audio_capture::{channel_name, AudioSource};
+
 #[derive(Copy, Clone)]
 enum Key {
     Single,
@@ -85,6 +87,16 @@ impl Default for Layout {
 }
 
 impl Layout {
+    pub fn rgb_frame(&self) -> Vec<u8> {
+        self.colors
+            .iter()
+            .flat_map(|color| match color {
+                Color::Rgb(r, g, b) => [*r, *g, *b],
+                _ => [0, 0, 0],
+            })
+            .collect()
+    }
+
     pub fn print(&self) -> std::io::Result<()> {
         for row in &self.rows {
             for key in row {
@@ -121,6 +133,108 @@ impl<'a> Widget for LayoutWidget<'a> {
     }
 }
 
+pub struct ChannelMetersWidget<'a> {
+    pub levels: &'a [f32],
+}
+
+impl<'a> Widget for ChannelMetersWidget<'a> {
+    fn render(self, area: Rect, buf: &mut Buffer)
+    where
+        Self: Sized,
+    {
+        const WIDTH: usize = 8;
+        let mut x = 0;
+        for (channel, level) in self.levels.iter().enumerate() {
+            let filled = ((level.clamp(0.0, 1.0) * WIDTH as f32).round() as usize).min(WIDTH);
+            let meter = format!(
+                "{:>3} {}{} ",
+                channel_name(channel, self.levels.len()),
+                "⣿".repeat(filled),
+                "⠤".repeat(WIDTH - filled)
+            );
+            let line = Line::from(meter).style(Style::default().fg(Color::Green));
+            if x >= area.width {
+                break;
+            }
+            buf.set_line(area.x + x, area.y, &line, area.width - x);
+            x += line.width() as u16;
+        }
+    }
+}
+
+pub struct DevicePicker {
+    pub source: AudioSource,
+    pub devices: Vec<String>,
+    selected: usize,
+}
+
+impl DevicePicker {
+    pub fn new(source: AudioSource, devices: Vec<String>) -> Self {
+        Self {
+            source,
+            devices,
+            selected: 0,
+        }
+    }
+
+    pub fn next(&mut self) {
+        self.selected = (self.selected + 1).min(self.devices.len());
+    }
+
+    pub fn previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    // The first entry follows the system default device.
+    pub fn choice(&self) -> Option<&str> {
+        self.selected
+            .checked_sub(1)
+            .and_then(|index| self.devices.get(index))
+            .map(String::as_str)
+    }
+}
+
+pub struct DevicePickerWidget<'a> {
+    pub picker: &'a DevicePicker,
+    pub current: Option<&'a str>,
+}
+
+impl<'a> Widget for DevicePickerWidget<'a> {
+    fn render(self, area: Rect, buf: &mut Buffer)
+    where
+        Self: Sized,
+    {
+        let title = format!(
+            " {} devices - Tab: {}, Enter: select, Esc: close ",
+            self.picker.source,
+            match self.picker.source {
+                AudioSource::Output => AudioSource::Input,
+                AudioSource::Input => AudioSource::Output,
+            }
+        );
+        let block = Block::default().borders(Borders::ALL).title(title);
+        let inner = block.inner(area);
+        Clear.render(area, buf);
+        block.render(area, buf);
+
+        let entries =
+            std::iter::once("System default").chain(self.picker.devices.iter().map(String::as_str));
+        for (index, name) in entries.enumerate().take(inner.height as usize) {
+            let marker = if index > 0 && Some(name) == self.current {
+                "*"
+            } else {
+                " "
+            };
+            let mut style = Style::default();
+            if index == self.picker.selected {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            let line = Line::from(format!("{} {}", marker, name)).style(style);
+            buf.set_line(inner.x, inner.y + index as u16, &line, inner.width);
+        }
+    }
+}
+
 pub struct VUMeterEmulator {
     pub smooth: f32,
     pub average_gain: f32,
@@ -192,7 +306,7 @@ impl Default for VUMeterEmulator {
     }
 }
 
-fn to_rgb(h: f32, s: f32, v: f32) -> Color {
+pub fn to_rgb(h: f32, s: f32, v: f32) -> Color {
     let range = (h / 60.0) as u8;
     let c = v * s;
     let x = c * (1.0 - (((h / 60.0) % 2.0) - 1.0).abs());
@@ -0,0 +1,42 @@
+use std::f32::consts::PI;
+
+use qmk_colormusic::{
+    bands::BandProcessor,
+    protocol::{bands_command, Command, Protocol, MAX_BANDS},
+};
+
+fn sine(frequency: f32, sample_rate: u32, seconds: f32) -> Vec<f32> {
+    (0..(sample_rate as f32 * seconds) as usize)
+        .flat_map(|i| {
+            let sample = (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin();
+            [sample, sample]
+        })
+        .collect()
+}
+
+#[test]
+fn sine_energy_lands_in_nearest_band() {
+    let mut processor = BandProcessor::default();
+    let centers = processor.center_frequencies(48000);
+    let target = centers.len() / 2;
+    processor.process_samples(&sine(centers[target], 48000, 0.5), 2, 48000);
+
+    let bands = processor.get_bands_u8();
+    let loudest = (0..bands.len()).max_by_key(|band| bands[*band]).unwrap();
+    assert_eq!(loudest, target);
+    assert!(bands[target] > 200);
+    assert!(bands[0] < bands[target] / 2);
+}
+
+#[test]
+fn bands_command_survives_framing() {
+    let protocol = Protocol::default();
+    let command = bands_command(&[1, 2, 3, 250]);
+    let report = protocol.prepare_command(&command).unwrap();
+    assert_eq!(protocol.to_command(&report[1..]).unwrap(), command);
+
+    let Command::Bands { count, .. } = bands_command(&[7; 40]) else {
+        unreachable!()
+    };
+    assert_eq!(count as usize, MAX_BANDS);
+}
@@ -0,0 +1,72 @@
+use qmk_colormusic::{
+    beat::{Beat, BeatDetector},
+    protocol::{beat_command, Command},
+};
+
+fn clicks(bpm: f32, sample_rate: u32, seconds: f32) -> Vec<f32> {
+    let period = (60.0 / bpm * sample_rate as f32) as usize;
+    let click = sample_rate as usize / 200;
+    (0..(sample_rate as f32 * seconds) as usize)
+        .map(|i| {
+            let offset = i % period;
+            if offset < click {
+                0.8 * (1.0 - offset as f32 / click as f32) * if i % 2 == 0 { 1.0 } else { -1.0 }
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+fn detect(detector: &mut BeatDetector, signal: &[f32], sample_rate: u32) -> Vec<Beat> {
+    signal
+        .chunks(512)
+        .filter_map(|buffer| {
+            detector.process_samples(buffer, 1, sample_rate);
+            detector.take_beat()
+        })
+        .collect()
+}
+
+#[test]
+fn tracks_click_tempo() {
+    let mut detector = BeatDetector::default();
+    let beats = detect(&mut detector, &clicks(120.0, 48000, 8.0), 48000);
+
+    assert!((15..=17).contains(&beats.len()), "{} beats", beats.len());
+    assert!(
+        (detector.bpm() - 120.0).abs() < 3.0,
+        "{} bpm",
+        detector.bpm()
+    );
+    assert_eq!(
+        beat_command(beats.last().unwrap()),
+        Command::Beat {
+            strength: (beats.last().unwrap().strength * 255.0) as u8,
+            bpm: detector.bpm().round() as u8,
+        }
+    );
+}
+
+#[test]
+fn ignores_silence() {
+    let mut detector = BeatDetector::default();
+    let beats = detect(&mut detector, &vec![0f32; 48000 * 4], 48000);
+
+    assert!(beats.is_empty());
+    assert_eq!(detector.bpm(), 0.0);
+}
+
+#[test]
+fn waits_for_history_before_first_beat() {
+    let mut detector = BeatDetector::default();
+    // The only click starts the signal.
+    assert!(detect(&mut detector, &clicks(120.0, 48000, 0.4), 48000).is_empty());
+
+    let beats = detect(&mut detector, &clicks(120.0, 48000, 2.0), 48000);
+    assert!(!beats.is_empty());
+
+    // After a gap in the stream the history starts over.
+    detector.clear();
+    assert!(detect(&mut detector, &clicks(120.0, 48000, 0.4), 48000).is_empty());
+}
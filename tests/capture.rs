@@ -0,0 +1,165 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    rc::Rc,
+    sync::mpsc::{self, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use cpal::{BackendSpecificError, StreamError};
+use qmk_colormusic::{
+    analyzer::{Analyzer, Snapshot},
+    audio_capture::{AudioCapture, AudioConfig, Backoff, CaptureStatus, StreamFault},
+    pipeline::Pipeline,
+};
+
+#[test]
+fn classifies_stream_errors() {
+    assert_eq!(
+        StreamFault::classify(&StreamError::DeviceNotAvailable),
+        StreamFault::DeviceLost
+    );
+    let backend = StreamError::BackendSpecific {
+        err: BackendSpecificError {
+            description: "xrun".to_owned(),
+        },
+    };
+    assert_eq!(StreamFault::classify(&backend), StreamFault::Transient);
+}
+
+#[test]
+fn backs_off_exponentially_up_to_limit() {
+    let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(500));
+    let delays: Vec<u128> = (0..5).map(|_| backoff.next_delay().as_millis()).collect();
+    assert_eq!(delays, [100, 200, 400, 500, 500]);
+    assert_eq!(backoff.attempt(), 5);
+
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+}
+
+struct FakeDevice {
+    // Results of the next opens, an empty queue opens successfully.
+    failures: VecDeque<bool>,
+    opened: u32,
+    errors: Option<Sender<StreamError>>,
+}
+
+fn fake_capture() -> (AudioCapture<Snapshot, ()>, Rc<RefCell<FakeDevice>>) {
+    let device = Rc::new(RefCell::new(FakeDevice {
+        failures: VecDeque::new(),
+        opened: 0,
+        errors: None,
+    }));
+    let (tx, _) = mpsc::channel();
+    let pipeline = Pipeline::spawn(Analyzer::default(), Analyzer::snapshot, tx);
+    let open_device = device.clone();
+    let capture = AudioCapture::with_open_stream(
+        pipeline,
+        AudioConfig::default(),
+        Box::new(move |_, _, _, errors| {
+            let mut device = open_device.borrow_mut();
+            device.opened += 1;
+            device.errors = Some(errors);
+            if device.failures.pop_front().unwrap_or(false) {
+                bail!("Device busy");
+            }
+            Ok(("Fake".to_owned(), ()))
+        }),
+    )
+    .with_backoff(Backoff::new(
+        Duration::from_millis(20),
+        Duration::from_millis(500),
+    ));
+    (capture, device)
+}
+
+fn report(device: &RefCell<FakeDevice>, err: StreamError) {
+    device.borrow().errors.as_ref().unwrap().send(err).unwrap();
+}
+
+fn backend_error() -> StreamError {
+    StreamError::BackendSpecific {
+        err: BackendSpecificError {
+            description: "xrun".to_owned(),
+        },
+    }
+}
+
+fn retry_in(status: &CaptureStatus) -> (u32, Duration) {
+    match status {
+        CaptureStatus::Recovering {
+            attempt, retry_at, ..
+        } => (*attempt, retry_at.saturating_duration_since(Instant::now())),
+        status => panic!("not recovering: {}", status),
+    }
+}
+
+#[test]
+fn recovers_from_a_lost_device_with_backoff() {
+    let (mut capture, device) = fake_capture();
+    capture.switch(AudioConfig::default()).unwrap();
+    assert_eq!(capture.status(), &CaptureStatus::Running);
+    assert_eq!(capture.device_name(), Some("Fake"));
+
+    // Repeated reports of the same loss schedule a single retry.
+    report(&device, StreamError::DeviceNotAvailable);
+    report(&device, StreamError::DeviceNotAvailable);
+    capture.poll();
+    let (attempt, delay) = retry_in(capture.status());
+    assert_eq!(attempt, 1);
+    assert!(delay <= Duration::from_millis(20), "{:?}", delay);
+    assert_eq!(device.borrow().opened, 1);
+
+    // The first restart fails and waits twice as long.
+    device.borrow_mut().failures.push_back(true);
+    thread::sleep(Duration::from_millis(25));
+    capture.poll();
+    let (attempt, delay) = retry_in(capture.status());
+    assert_eq!(attempt, 2);
+    assert!(delay > Duration::from_millis(20), "{:?}", delay);
+    assert_eq!(device.borrow().opened, 2);
+
+    thread::sleep(delay + Duration::from_millis(5));
+    capture.poll();
+    assert_eq!(capture.status(), &CaptureStatus::Running);
+    assert_eq!(device.borrow().opened, 3);
+
+    // A successful restart starts the backoff over.
+    report(&device, StreamError::DeviceNotAvailable);
+    capture.poll();
+    assert_eq!(retry_in(capture.status()).0, 1);
+}
+
+#[test]
+fn clears_degraded_status_once_errors_stop() {
+    let (mut capture, device) = fake_capture();
+    capture.switch(AudioConfig::default()).unwrap();
+
+    report(&device, backend_error());
+    capture.poll();
+    assert!(matches!(capture.status(), CaptureStatus::Degraded(_)));
+
+    thread::sleep(Duration::from_millis(1100));
+    capture.poll();
+    assert_eq!(capture.status(), &CaptureStatus::Running);
+    assert_eq!(device.borrow().opened, 1);
+}
+
+#[test]
+fn restarts_after_a_burst_of_backend_errors() {
+    let (mut capture, device) = fake_capture();
+    capture.switch(AudioConfig::default()).unwrap();
+
+    for _ in 0..4 {
+        report(&device, backend_error());
+    }
+    capture.poll();
+    assert!(matches!(capture.status(), CaptureStatus::Degraded(_)));
+
+    report(&device, backend_error());
+    capture.poll();
+    assert_eq!(retry_in(capture.status()).0, 1);
+}
@@ -0,0 +1,182 @@
+// Shared by several test crates, each one only uses part of it.
+#![allow(dead_code)]
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use qmk_colormusic::{
+    handshake::FirmwareInfo,
+    protocol::{Capabilities, Command, Protocol, PROTOCOL_VERSION},
+    transport::Transport,
+};
+
+#[derive(Default)]
+struct MockState {
+    received: Vec<Command>,
+    pending: VecDeque<Vec<u8>>,
+    handshaken: bool,
+    unplugged: bool,
+    frame: Vec<u8>,
+    frames: Vec<Vec<u8>>,
+}
+
+#[derive(Clone)]
+pub struct MockKeyboard {
+    protocol: Arc<Protocol>,
+    state: Arc<(Mutex<MockState>, Condvar)>,
+    // None never answers the handshake request.
+    handshake_status: Option<u8>,
+    firmware: Option<FirmwareInfo>,
+}
+
+impl MockKeyboard {
+    pub fn new() -> Self {
+        Self::with_firmware(FirmwareInfo {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::host(),
+        })
+    }
+
+    pub fn with_firmware(firmware: FirmwareInfo) -> Self {
+        Self {
+            protocol: Arc::new(Protocol::default()),
+            state: Arc::new((Mutex::new(MockState::default()), Condvar::new())),
+            handshake_status: Some(0x80),
+            firmware: Some(firmware),
+        }
+    }
+
+    pub fn legacy() -> Self {
+        Self {
+            firmware: None,
+            ..Self::new()
+        }
+    }
+
+    pub fn with_handshake_status(status: u8) -> Self {
+        Self {
+            handshake_status: Some(status),
+            ..Self::new()
+        }
+    }
+
+    pub fn silent() -> Self {
+        Self {
+            handshake_status: None,
+            ..Self::new()
+        }
+    }
+
+    pub fn received(&self) -> Vec<Command> {
+        self.state.0.lock().unwrap().received.clone()
+    }
+
+    pub fn is_handshaken(&self) -> bool {
+        self.state.0.lock().unwrap().handshaken
+    }
+
+    pub fn frames(&self) -> Vec<Vec<u8>> {
+        self.state.0.lock().unwrap().frames.clone()
+    }
+
+    pub fn unplug(&self) {
+        self.state.0.lock().unwrap().unplugged = true;
+    }
+
+    pub fn send(&self, command: &Command) {
+        let (state, condvar) = &*self.state;
+        let report = self.protocol.prepare_command(command).unwrap();
+        // Input reports are delivered without the leading report id.
+        state
+            .lock()
+            .unwrap()
+            .pending
+            .push_back(report[1..].to_vec());
+        condvar.notify_all();
+    }
+
+    fn respond(&self, command: &Command) {
+        match command {
+            Command::Handshake { status: 0x7F } => {
+                if let Some(status) = self.handshake_status {
+                    self.send(&Command::Handshake { status })
+                }
+            }
+            Command::Handshake { status: 0x81 } => {
+                self.state.0.lock().unwrap().handshaken = true;
+            }
+            Command::CustomData {
+                length,
+                sequence,
+                count,
+                data,
+            } => {
+                let mut state = self.state.0.lock().unwrap();
+                if *sequence == 0 {
+                    state.frame.clear();
+                }
+                state.frame.extend_from_slice(&data[..*length as usize]);
+                if sequence + 1 == *count {
+                    let frame = std::mem::take(&mut state.frame);
+                    state.frames.push(frame);
+                }
+            }
+            Command::Version { .. } => {
+                if let Some(firmware) = self.firmware {
+                    self.send(&Command::Version {
+                        version: firmware.version,
+                        capabilities: firmware.capabilities.0,
+                    })
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Default for MockKeyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for MockKeyboard {
+    fn write_report(&self, data: &[u8]) -> Result<usize> {
+        if self.state.0.lock().unwrap().unplugged {
+            bail!("Mock keyboard is unplugged");
+        }
+        let Some(report) = data.get(1..) else {
+            bail!("Empty report");
+        };
+        let command = self.protocol.to_command(report)?;
+        self.state.0.lock().unwrap().received.push(command);
+        self.respond(&command);
+        Ok(data.len())
+    }
+
+    fn read_report(&self, buf: &mut [u8], timeout: Option<Duration>) -> Result<usize> {
+        let (state, condvar) = &*self.state;
+        let mut state = state.lock().unwrap();
+        state = match timeout {
+            Some(timeout) => {
+                condvar
+                    .wait_timeout_while(state, timeout, |s| s.pending.is_empty())
+                    .unwrap()
+                    .0
+            }
+            None => condvar.wait_while(state, |s| s.pending.is_empty()).unwrap(),
+        };
+        match state.pending.pop_front() {
+            Some(report) => {
+                let length = report.len().min(buf.len());
+                buf[..length].copy_from_slice(&report[..length]);
+                Ok(length)
+            }
+            None => Ok(0),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+use qmk_colormusic::{
+    audio_capture::{AudioConfig, AudioSource},
+    config::Config,
+    keyboard::{ChannelMap, DEFAULT_CONTROL_TIMEOUT},
+};
+
+#[test]
+fn parses_keyboard_list() {
+    let config: Config = toml::from_str(
+        r#"
+        [[keyboards]]
+        name = "Keyboard"
+        vendor_id = 0x19F5
+        product_id = 0x3245
+
+        [[keyboards]]
+        name = "Macropad"
+        serial = "MP-01"
+        channels = "right"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.keyboards.len(), 2);
+    assert_eq!(config.keyboards[0].filter.vendor_id, Some(0x19F5));
+    assert_eq!(config.keyboards[0].filter.usage_page, Some(0xFF60));
+    assert_eq!(config.keyboards[0].channels, ChannelMap::Stereo);
+    assert_eq!(config.keyboards[1].filter.serial.as_deref(), Some("MP-01"));
+    assert_eq!(config.keyboards[1].channels, ChannelMap::Right);
+}
+
+#[test]
+fn parses_audio_device_choice() {
+    let config: Config = toml::from_str(
+        r#"
+        [audio]
+        host = "ALSA"
+        device = "monitor"
+        source = "input"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.audio.host.as_deref(), Some("ALSA"));
+    assert_eq!(config.audio.device.as_deref(), Some("monitor"));
+    assert_eq!(config.audio.source, AudioSource::Input);
+    assert_eq!(
+        toml::from_str::<AudioConfig>(&toml::to_string(&config.audio).unwrap()).unwrap(),
+        config.audio
+    );
+}
+
+#[test]
+fn rejects_db_floor_above_full_scale() {
+    for floor in ["0.0", "12.0", "nan"] {
+        for key in ["db_floor", "level_db_floor"] {
+            let config = format!("[analysis]\n{} = {}", key, floor);
+            assert!(toml::from_str::<Config>(&config).is_err(), "{}", config);
+        }
+    }
+    let config: Config = toml::from_str("[analysis]\nlevel_db_floor = -48.0").unwrap();
+    assert_eq!(config.analysis.level_db_floor, -48.0);
+}
+
+#[test]
+fn rejects_empty_frequency_range() {
+    for range in [
+        "min_frequency = 0.0",
+        "min_frequency = -20.0",
+        "min_frequency = 5000.0\nmax_frequency = 5000.0",
+        "max_frequency = 10.0",
+        "max_frequency = nan",
+    ] {
+        let config = format!("[analysis]\n{}", range);
+        assert!(toml::from_str::<Config>(&config).is_err(), "{}", config);
+    }
+    let config: Config =
+        toml::from_str("[analysis]\nmin_frequency = 40.0\nmax_frequency = 8000.0").unwrap();
+    assert_eq!(config.analysis.min_frequency, 40.0);
+}
+
+#[test]
+fn parses_control_timeout() {
+    let config: Config = toml::from_str(
+        r#"
+        [[keyboards]]
+        control_timeout_ms = 500
+
+        [[keyboards]]
+        "#,
+    )
+    .unwrap();
+
+    let outputs: Vec<_> = config.keyboards.iter().map(|k| k.output()).collect();
+    assert_eq!(outputs[0].control_timeout, Duration::from_millis(500));
+    assert_eq!(outputs[1].control_timeout, DEFAULT_CONTROL_TIMEOUT);
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use qmk_colormusic::{
+    analyzer::{SharedSnapshot, Snapshot},
+    handshake::FirmwareInfo,
+    keyboard::{hid_thread, IdleAction, IdleConfig, OutputConfig},
+    protocol::{Capabilities, Command, Protocol, ThreadCommand, PROTOCOL_VERSION},
+    scheduler::SharedSendStats,
+};
+
+use common::MockKeyboard;
+
+fn stream_to_keyboard(
+    snapshot: SharedSnapshot,
+    output: OutputConfig,
+    run_for: Duration,
+) -> Vec<Command> {
+    let keyboard = MockKeyboard::new();
+    let firmware = FirmwareInfo {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::host(),
+    };
+    let (tx, rx) = mpsc::channel();
+    let hid_keyboard = keyboard.clone();
+    let handle = thread::spawn(move || {
+        hid_thread(
+            &hid_keyboard,
+            &Protocol::default(),
+            snapshot,
+            &rx,
+            &firmware,
+            &output,
+            &SharedSendStats::default(),
+        )
+    });
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    thread::sleep(run_for);
+    tx.send(ThreadCommand::Stop).unwrap();
+    handle.join().unwrap().unwrap();
+    keyboard.received()
+}
+
+#[test]
+fn encodes_control_commands() {
+    let protocol = Protocol::default();
+    for command in [
+        Command::Take { timeout: 20 },
+        Command::Heartbeat,
+        Command::Release,
+    ] {
+        let report = protocol.prepare_command(&command).unwrap();
+        assert_eq!(protocol.to_command(&report[1..]).unwrap(), command);
+    }
+    assert!(!Capabilities::legacy().supports(&Command::Heartbeat));
+}
+
+#[test]
+fn heartbeats_keep_control_while_levels_hold() {
+    let output = OutputConfig {
+        control_timeout: Duration::from_millis(200),
+        ..OutputConfig::default()
+    };
+    let received = stream_to_keyboard(
+        SharedSnapshot::default(),
+        output,
+        Duration::from_millis(180),
+    );
+
+    assert_eq!(received[0], Command::Take { timeout: 2 });
+    assert_eq!(received[1], Command::RMS { left: 0, right: 0 });
+    let heartbeats = received[2..received.len() - 1].to_vec();
+    // One every 50 ms, the exact count depends on scheduling.
+    assert!(heartbeats.len() >= 2, "{:?}", received);
+    assert!(heartbeats.iter().all(|c| *c == Command::Heartbeat));
+    assert_eq!(received.last(), Some(&Command::Release));
+}
+
+#[test]
+fn idle_release_hands_back_control() {
+    let snapshot = Arc::new(ArcSwap::from_pointee(Snapshot {
+        level: (0.5, 0.5),
+        silence: Duration::from_secs(60),
+        ..Snapshot::default()
+    }));
+    let output = OutputConfig {
+        idle: IdleConfig {
+            action: IdleAction::Release,
+            delay_secs: 30,
+        },
+        control_timeout: Duration::from_millis(200),
+        ..OutputConfig::default()
+    };
+    let received = stream_to_keyboard(snapshot, output, Duration::from_millis(100));
+
+    // No levels and no heartbeats while the firmware is in charge.
+    assert_eq!(received, [Command::Take { timeout: 2 }, Command::Release]);
+}
+
+#[test]
+fn releases_when_the_host_goes_away() {
+    let firmware = FirmwareInfo {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::host(),
+    };
+    let keyboard = MockKeyboard::new();
+    let stats = SharedSendStats::default();
+    let (tx, rx) = mpsc::channel();
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    drop(tx);
+    hid_thread(
+        &keyboard,
+        &Protocol::default(),
+        SharedSnapshot::default(),
+        &rx,
+        &firmware,
+        &OutputConfig::default(),
+        &stats,
+    )
+    .unwrap();
+
+    assert_eq!(
+        keyboard.received(),
+        [
+            Command::Take { timeout: 20 },
+            Command::RMS { left: 0, right: 0 },
+            Command::Release
+        ]
+    );
+    assert_eq!(stats.lock().unwrap().sent, 1);
+}
@@ -0,0 +1,116 @@
+mod common;
+
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use qmk_colormusic::{
+    analyzer::Snapshot,
+    handshake::FirmwareInfo,
+    keyboard::{hid_thread, OutputConfig},
+    protocol::{Capabilities, Command, Protocol, ThreadCommand, PROTOCOL_VERSION},
+    scheduler::SharedSendStats,
+};
+
+use common::MockKeyboard;
+
+// Streams a quiet stereo level, the keyboard sends each event between two analyses.
+fn stream_with_events(events: &[Command]) -> Vec<Command> {
+    let keyboard = MockKeyboard::new();
+    let snapshot = Arc::new(ArcSwap::from_pointee(Snapshot {
+        level: (0.25, 0.125),
+        bands: vec![64; 4],
+        ..Snapshot::default()
+    }));
+    let firmware = FirmwareInfo {
+        version: PROTOCOL_VERSION,
+        capabilities: Capabilities::host(),
+    };
+    let output = OutputConfig {
+        rate: 0,
+        ..OutputConfig::default()
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let hid_keyboard = keyboard.clone();
+    let handle = thread::spawn(move || {
+        hid_thread(
+            &hid_keyboard,
+            &Protocol::default(),
+            snapshot,
+            &rx,
+            &firmware,
+            &output,
+            &SharedSendStats::default(),
+        )
+    });
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    for event in events {
+        thread::sleep(Duration::from_millis(60));
+        keyboard.send(event);
+        thread::sleep(Duration::from_millis(60));
+        tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    }
+    tx.send(ThreadCommand::Stop).unwrap();
+    handle.join().unwrap().unwrap();
+
+    keyboard
+        .received()
+        .into_iter()
+        .filter(|command| !matches!(command, Command::Take { .. } | Command::Heartbeat))
+        .collect()
+}
+
+#[test]
+fn sensitivity_raises_levels() {
+    let received = stream_with_events(&[Command::Sensitivity, Command::Sensitivity]);
+
+    assert_eq!(
+        received,
+        [
+            Command::RMS {
+                left: 63,
+                right: 31
+            },
+            Command::RMS {
+                left: 127,
+                right: 63
+            },
+            Command::RMS {
+                left: 255,
+                right: 127
+            },
+            Command::Release
+        ]
+    );
+}
+
+#[test]
+fn cycles_through_supported_modes() {
+    let received = stream_with_events(&[Command::CycleMode, Command::CycleMode]);
+
+    assert!(matches!(received[0], Command::RMS { .. }));
+    assert!(matches!(
+        received[1],
+        Command::CustomData { sequence: 0, .. }
+    ));
+    let bands = received
+        .iter()
+        .find(|command| matches!(command, Command::Bands { .. }));
+    assert!(matches!(bands, Some(Command::Bands { count: 4, .. })));
+}
+
+#[test]
+fn pause_hands_lighting_back_and_resumes() {
+    let received = stream_with_events(&[Command::Pause, Command::Pause]);
+    let rms = Command::RMS {
+        left: 63,
+        right: 31,
+    };
+
+    // Resuming takes control again, the unchanged levels follow right away.
+    assert_eq!(received, [rms, Command::Release, rms, Command::Release]);
+}
@@ -0,0 +1,89 @@
+use qmk_colormusic::{
+    audio_capture::{LevelMetric, RmsProcessor},
+    generator::{Generator, GeneratorConfig, Signal},
+    playback::SampleSource,
+};
+
+fn generate(signal: Signal, settings: &GeneratorConfig) -> Vec<f32> {
+    let mut generator = Generator::new(signal, settings);
+    let mut samples = Vec::new();
+    while generator.read(&mut samples).unwrap() {}
+    samples
+}
+
+fn mono(duration_secs: f32) -> GeneratorConfig {
+    GeneratorConfig {
+        channels: 1,
+        duration_secs,
+        ..GeneratorConfig::default()
+    }
+}
+
+#[test]
+fn noise_is_reproducible() {
+    let settings = mono(0.5);
+    for signal in [Signal::White, Signal::Pink] {
+        let samples = generate(signal, &settings);
+        assert_eq!(samples.len(), 24000);
+        assert_eq!(samples, generate(signal, &settings));
+        assert!(samples.iter().all(|s| s.abs() <= settings.amplitude));
+        assert!(samples.iter().any(|s| s.abs() > settings.amplitude / 4.0));
+    }
+}
+
+#[test]
+fn clicks_follow_tempo() {
+    let settings = GeneratorConfig {
+        bpm: 240.0,
+        ..mono(2.0)
+    };
+    let samples = generate(Signal::Clicks, &settings);
+    let onsets = samples
+        .windows(2)
+        .filter(|pair| pair[0] == 0.0 && pair[1] > 0.0)
+        .count();
+    // Four beats per second, the first one starts at sample zero.
+    assert_eq!(onsets + 1, 8);
+}
+
+#[test]
+fn steps_through_levels() {
+    let settings = GeneratorConfig {
+        amplitude: 1.0,
+        period_secs: 0.7,
+        ..mono(0.7)
+    };
+    let samples = generate(Signal::Steps, &settings);
+    // Each 100 ms step measured in its second half.
+    let levels: Vec<f32> = samples
+        .chunks(4800)
+        .map(|step| {
+            let mut processor = RmsProcessor::new();
+            processor.process_samples(&step[2400..], 1, 48000);
+            processor.get_dbfs(LevelMetric::Peak).0
+        })
+        .collect();
+    let expected = [-60.0, -48.0, -36.0, -24.0, -12.0, -6.0, 0.0];
+    for (level, expected) in levels.iter().zip(expected) {
+        assert!((level - expected).abs() < 0.1, "{:?}", levels);
+    }
+}
+
+#[test]
+fn sweeps_up_in_frequency() {
+    let settings = GeneratorConfig {
+        min_frequency: 100.0,
+        max_frequency: 1000.0,
+        period_secs: 1.0,
+        ..mono(1.0)
+    };
+    let samples = generate(Signal::Sweep, &settings);
+    let crossings = |part: &[f32]| {
+        part.windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count()
+    };
+    // Roughly 100 Hz at the start and 1 kHz at the end, 50 ms windows.
+    assert!((4..=6).contains(&crossings(&samples[..2400])));
+    assert!((45..=50).contains(&crossings(&samples[45600..])));
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use std::time::Duration;
+
+use qmk_colormusic::{
+    handshake::{process_handshake, FirmwareInfo, HandshakeConfig, HandshakeError},
+    protocol::{frame_commands, Capabilities, Protocol},
+};
+
+use common::MockKeyboard;
+
+fn fast_config() -> HandshakeConfig {
+    HandshakeConfig {
+        deadline: Duration::from_millis(500),
+        response_timeout: Duration::from_millis(50),
+        retries: 3,
+    }
+}
+
+#[test]
+fn negotiates_firmware_capabilities() {
+    let firmware = FirmwareInfo {
+        version: 3,
+        capabilities: Capabilities(Capabilities::RMS),
+    };
+    let keyboard = MockKeyboard::with_firmware(firmware);
+    let negotiated = process_handshake(&keyboard, &Protocol::default(), &fast_config()).unwrap();
+
+    assert_eq!(negotiated, firmware);
+    assert!(!negotiated.supports(&frame_commands(&[0; 3])[0]));
+}
+
+#[test]
+fn falls_back_to_legacy_firmware() {
+    let keyboard = MockKeyboard::legacy();
+    let negotiated = process_handshake(&keyboard, &Protocol::default(), &fast_config()).unwrap();
+
+    assert!(keyboard.is_handshaken());
+    assert_eq!(negotiated, FirmwareInfo::legacy());
+}
+
+#[test]
+fn gives_up_after_wrong_status() {
+    let keyboard = MockKeyboard::with_handshake_status(0x42);
+    let result = process_handshake(&keyboard, &Protocol::default(), &fast_config());
+
+    assert!(matches!(result, Err(HandshakeError::RetriesExhausted(3))));
+    assert_eq!(keyboard.received().len(), 3);
+}
+
+#[test]
+fn gives_up_when_keyboard_stays_silent() {
+    let keyboard = MockKeyboard::silent();
+    let result = process_handshake(&keyboard, &Protocol::default(), &fast_config());
+
+    assert!(matches!(result, Err(HandshakeError::NoAnswer(3))));
+    assert!(!keyboard.is_handshaken());
+}
+
+#[test]
+fn times_out_while_keyboard_keeps_rejecting() {
+    let keyboard = MockKeyboard::with_handshake_status(0x42);
+    let config = HandshakeConfig {
+        retries: u32::MAX,
+        ..fast_config()
+    };
+    let result = process_handshake(&keyboard, &Protocol::default(), &config);
+
+    assert!(matches!(result, Err(HandshakeError::Timeout)));
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use cpal::{SampleRate, StreamConfig};
+use qmk_colormusic::{
+    analyzer::{AnalysisConfig, Analyzer, Snapshot},
+    audio_capture::Processor,
+    handshake::FirmwareInfo,
+    keyboard::{hid_thread, IdleAction, IdleConfig, OutputConfig},
+    pipeline::Pipeline,
+    protocol::{Command, Protocol, ThreadCommand},
+    scheduler::SharedSendStats,
+};
+
+use common::MockKeyboard;
+
+fn stereo_config() -> StreamConfig {
+    StreamConfig {
+        channels: 2,
+        sample_rate: SampleRate(48000),
+        buffer_size: cpal::BufferSize::Default,
+    }
+}
+
+fn idle_output(action: IdleAction) -> Vec<Command> {
+    let keyboard = MockKeyboard::new();
+    let snapshot = Arc::new(ArcSwap::from_pointee(Snapshot {
+        level: (0.5, 0.5),
+        silence: Duration::from_secs(60),
+        ..Snapshot::default()
+    }));
+    let output = OutputConfig {
+        idle: IdleConfig {
+            action,
+            delay_secs: 30,
+        },
+        ..OutputConfig::default()
+    };
+
+    let (tx, rx) = mpsc::channel();
+    let hid_keyboard = keyboard.clone();
+    let handle = thread::spawn(move || {
+        hid_thread(
+            &hid_keyboard,
+            &Protocol::default(),
+            snapshot,
+            &rx,
+            &FirmwareInfo::legacy(),
+            &output,
+            &SharedSendStats::default(),
+        )
+    });
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    drop(tx);
+    handle.join().unwrap().unwrap();
+    keyboard.received()
+}
+
+#[test]
+fn counts_silence_until_signal_returns() {
+    let mut analyzer = Analyzer::default();
+    analyzer.process_samples(&[0f32; 9600], &stereo_config());
+    assert_eq!(analyzer.silence(), Duration::from_millis(100));
+
+    analyzer.process_samples(&[0.5f32, -0.5].repeat(480), &stereo_config());
+    assert_eq!(analyzer.silence(), Duration::ZERO);
+}
+
+#[test]
+fn timeout_clears_levels() {
+    let mut analyzer = Analyzer::default();
+    analyzer.process_samples(&[0.5f32, -0.5].repeat(480), &stereo_config());
+    assert!(analyzer.snapshot().level.0 > 0.0);
+
+    analyzer.process_timeout(Duration::from_millis(50));
+    let snapshot = analyzer.snapshot();
+    assert_eq!(snapshot.level, (0.0, 0.0));
+    assert_eq!(snapshot.silence, Duration::from_millis(50));
+}
+
+#[test]
+fn worker_ticks_without_input() {
+    let config = AnalysisConfig {
+        stream_timeout_ms: 20,
+        ..AnalysisConfig::default()
+    };
+    let (tx, rx) = mpsc::channel();
+    let pipeline = Pipeline::spawn(Analyzer::new(&config), Analyzer::snapshot, tx);
+    let _input = pipeline.input(&stereo_config());
+
+    for _ in 0..3 {
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(2)).unwrap(),
+            ThreadCommand::ProcessorComplete
+        ));
+    }
+    assert!(pipeline.snapshot().load().silence > Duration::ZERO);
+}
+
+#[test]
+fn idle_keyboard_breathes_or_rests() {
+    let received = idle_output(IdleAction::Animation);
+    assert_eq!(received.len(), 1);
+    assert!(matches!(received[0], Command::RMS { left, right } if left == right && left < 128));
+
+    // Without control support the keyboard is left at rest instead of frozen.
+    assert_eq!(
+        idle_output(IdleAction::Release),
+        [Command::RMS { left: 0, right: 0 }]
+    );
+    assert_eq!(
+        idle_output(IdleAction::None),
+        [Command::RMS {
+            left: 127,
+            right: 127
+        }]
+    );
+}
@@ -0,0 +1,244 @@
+mod common;
+
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use qmk_colormusic::{
+    analyzer::{Analyzer, SharedSnapshot, Snapshot},
+    beat::Beat,
+    handshake::{process_handshake, FirmwareInfo, HandshakeConfig},
+    keyboard::{fan_out, hid_thread, ChannelMap, OutputConfig, OutputMode},
+    protocol::{Capabilities, Command, Protocol, ThreadCommand, PROTOCOL_VERSION},
+    scheduler::SharedSendStats,
+    visualizer::{Layout, VUMeterEmulator},
+};
+
+use common::MockKeyboard;
+
+fn stereo_snapshot() -> SharedSnapshot {
+    let mut analyzer = Analyzer::default();
+    analyzer
+        .rms
+        .process_samples(&[0.5f32, 0.25, -0.5, -0.25].repeat(64), 2, 48000);
+    Arc::new(ArcSwap::from_pointee(analyzer.snapshot()))
+}
+
+#[test]
+fn handshake_with_mock_keyboard() {
+    let keyboard = MockKeyboard::new();
+    process_handshake(&keyboard, &Protocol::default(), &HandshakeConfig::default()).unwrap();
+
+    assert!(keyboard.is_handshaken());
+    assert_eq!(
+        keyboard.received(),
+        vec![
+            Command::Handshake { status: 0x7F },
+            Command::Handshake { status: 0x81 },
+            Command::Version {
+                version: PROTOCOL_VERSION,
+                capabilities: Capabilities::host().0
+            }
+        ]
+    );
+}
+
+#[test]
+fn rms_streaming_to_mock_keyboard() {
+    let keyboard = MockKeyboard::new();
+    let protocol = Protocol::default();
+    process_handshake(&keyboard, &protocol, &HandshakeConfig::default()).unwrap();
+
+    let snapshot = stereo_snapshot();
+
+    let (tx, rx) = mpsc::channel();
+    let hid_keyboard = keyboard.clone();
+    let handle = thread::spawn(move || {
+        hid_thread(
+            &hid_keyboard,
+            &protocol,
+            snapshot,
+            &rx,
+            &FirmwareInfo::legacy(),
+            &OutputConfig::default(),
+            &SharedSendStats::default(),
+        )
+    });
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    drop(tx);
+    handle.join().unwrap().unwrap();
+
+    let rms = Command::RMS {
+        left: 127,
+        right: 63,
+    };
+    // The second analysis carries the same levels and is not sent again.
+    assert_eq!(keyboard.received()[3..], [rms]);
+}
+
+#[test]
+fn fans_out_rms_to_every_keyboard() {
+    let keyboards = [
+        (MockKeyboard::new(), ChannelMap::Stereo),
+        (MockKeyboard::new(), ChannelMap::Right),
+    ];
+    let snapshot = stereo_snapshot();
+
+    let (tx, rx) = mpsc::channel();
+    let mut senders = Vec::new();
+    let mut handles = Vec::new();
+    for (keyboard, channels) in &keyboards {
+        let (keyboard_tx, keyboard_rx) = mpsc::channel();
+        senders.push(keyboard_tx);
+        let keyboard = keyboard.clone();
+        let channels = *channels;
+        let snapshot = snapshot.clone();
+        handles.push(thread::spawn(move || {
+            let protocol = Protocol::default();
+            let output = OutputConfig {
+                channels,
+                ..OutputConfig::default()
+            };
+            hid_thread(
+                &keyboard,
+                &protocol,
+                snapshot,
+                &keyboard_rx,
+                &FirmwareInfo::legacy(),
+                &output,
+                &SharedSendStats::default(),
+            )
+        }));
+    }
+    let fan_out_handle = thread::spawn(move || fan_out(rx, senders));
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    drop(tx);
+    fan_out_handle.join().unwrap();
+    for handle in handles {
+        handle.join().unwrap().unwrap();
+    }
+
+    assert_eq!(
+        keyboards[0].0.received(),
+        [Command::RMS {
+            left: 127,
+            right: 63
+        }]
+    );
+    assert_eq!(
+        keyboards[1].0.received(),
+        [Command::RMS {
+            left: 63,
+            right: 63
+        }]
+    );
+}
+
+#[test]
+fn streams_color_frames_to_mock_keyboard() {
+    let keyboard = MockKeyboard::new();
+    let protocol = Protocol::default();
+    let firmware = process_handshake(&keyboard, &protocol, &HandshakeConfig::default()).unwrap();
+
+    let snapshot = stereo_snapshot();
+    let loud = snapshot.load().level;
+    // Slightly quieter than the smoothed level, so the meter is only partly lit.
+    let quiet = (loud.0 * 0.98, loud.1 * 0.98);
+
+    let (tx, rx) = mpsc::channel();
+    let hid_keyboard = keyboard.clone();
+    let hid_snapshot = snapshot.clone();
+    let handle = thread::spawn(move || {
+        let output = OutputConfig {
+            mode: OutputMode::Frame,
+            rate: 0,
+            ..OutputConfig::default()
+        };
+        hid_thread(
+            &hid_keyboard,
+            &protocol,
+            hid_snapshot,
+            &rx,
+            &firmware,
+            &output,
+            &SharedSendStats::default(),
+        )
+    });
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    snapshot.store(Arc::new(Snapshot {
+        level: quiet,
+        ..Snapshot::default()
+    }));
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    drop(tx);
+    handle.join().unwrap().unwrap();
+
+    let mut layout = Layout::default();
+    let mut vu_emulator = VUMeterEmulator::default();
+    let expected: Vec<Vec<u8>> = [loud, quiet]
+        .into_iter()
+        .map(|level| {
+            vu_emulator.process(level, &mut layout.colors);
+            layout.rgb_frame()
+        })
+        .collect();
+    assert_eq!(keyboard.frames(), expected);
+    assert_eq!(expected[1].len(), 84 * 3);
+    let dark = expected[1]
+        .chunks(3)
+        .filter(|key| *key == [32, 0, 0])
+        .count();
+    assert!(dark > 0 && dark < 84, "{} dark keys", dark);
+}
+
+#[test]
+fn forwards_beats_only_to_capable_firmware() {
+    let beat = Beat {
+        strength: 1.0,
+        bpm: 128.0,
+    };
+    let firmwares = [
+        FirmwareInfo::legacy(),
+        FirmwareInfo {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::host(),
+        },
+    ];
+    for firmware in firmwares {
+        let keyboard = MockKeyboard::new();
+        let (tx, rx) = mpsc::channel();
+        tx.send(ThreadCommand::Beat(beat)).unwrap();
+        drop(tx);
+        hid_thread(
+            &keyboard,
+            &Protocol::default(),
+            SharedSnapshot::default(),
+            &rx,
+            &firmware,
+            &OutputConfig::default(),
+            &SharedSendStats::default(),
+        )
+        .unwrap();
+
+        let expected: &[Command] = if firmware.capabilities.has(Capabilities::BEAT) {
+            &[Command::Beat {
+                strength: 255,
+                bpm: 128,
+            }]
+        } else {
+            &[]
+        };
+        // Capable firmware is taken control of first and released at the end.
+        let received: Vec<Command> = keyboard
+            .received()
+            .into_iter()
+            .filter(|command| !matches!(command, Command::Take { .. } | Command::Release))
+            .collect();
+        assert_eq!(received, expected);
+    }
+}
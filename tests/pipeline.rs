@@ -0,0 +1,100 @@
+use std::{sync::mpsc, time::Duration};
+
+use cpal::{SampleRate, StreamConfig};
+use qmk_colormusic::{analyzer::Analyzer, pipeline::Pipeline, protocol::ThreadCommand};
+
+fn stream_config(channels: u16, sample_rate: u32) -> StreamConfig {
+    StreamConfig {
+        channels,
+        sample_rate: SampleRate(sample_rate),
+        buffer_size: cpal::BufferSize::Default,
+    }
+}
+
+fn stereo_config() -> StreamConfig {
+    stream_config(2, 48000)
+}
+
+fn wait_for_analysis(rx: &mpsc::Receiver<ThreadCommand>) {
+    while !matches!(
+        rx.recv_timeout(Duration::from_secs(2)).unwrap(),
+        ThreadCommand::ProcessorComplete
+    ) {}
+}
+
+#[test]
+fn worker_publishes_snapshots() {
+    let (tx, rx) = mpsc::channel();
+    let pipeline = Pipeline::spawn(Analyzer::default(), Analyzer::snapshot, tx);
+    let mut input = pipeline.input(&stereo_config());
+    let snapshot = pipeline.snapshot();
+    assert_eq!(snapshot.load().level, (0.0, 0.0));
+
+    input.push(&[0.5f32, 0.25, -0.5, -0.25].repeat(256));
+    wait_for_analysis(&rx);
+
+    assert_eq!(snapshot.load().level_u8(), (127, 63));
+    assert_eq!(pipeline.dropped_frames(), 0);
+}
+
+#[test]
+fn drops_whole_frames_when_full() {
+    let (tx, _rx) = mpsc::channel();
+    let pipeline = Pipeline::spawn(Analyzer::default(), Analyzer::snapshot, tx);
+    let mut input = pipeline.input(&stereo_config());
+
+    // One second of audio in a single callback, twice the queue capacity.
+    input.push(&[0i16; 96000]);
+
+    assert_eq!(pipeline.dropped_frames(), 24000);
+}
+
+fn level_after_push<S>(data: &[S]) -> (u8, u8)
+where
+    S: cpal::Sample + dasp_sample::ToSample<f32>,
+{
+    let (tx, rx) = mpsc::channel();
+    let pipeline = Pipeline::spawn(Analyzer::default(), Analyzer::snapshot, tx);
+    let mut input = pipeline.input(&stereo_config());
+    input.push(data);
+    wait_for_analysis(&rx);
+    pipeline.snapshot().load().level_u8()
+}
+
+#[test]
+fn converts_every_sample_format() {
+    let expected = (127, 63);
+    assert_eq!(level_after_push(&[64i8, 32, -64, -32].repeat(64)), expected);
+    assert_eq!(
+        level_after_push(&[1i64 << 62, 1 << 61, -(1 << 62), -(1 << 61)].repeat(64)),
+        expected
+    );
+    assert_eq!(level_after_push(&[192u8, 160, 64, 96].repeat(64)), expected);
+    assert_eq!(
+        level_after_push(&[49152u16, 40960, 16384, 24576].repeat(64)),
+        expected
+    );
+    assert_eq!(
+        level_after_push(&[0.5f64, 0.25, -0.5, -0.25].repeat(64)),
+        expected
+    );
+}
+
+#[test]
+fn switches_to_new_input() {
+    let (tx, rx) = mpsc::channel();
+    let pipeline = Pipeline::spawn(Analyzer::default(), Analyzer::snapshot, tx);
+    let mut stereo = pipeline.input(&stereo_config());
+    stereo.push(&[0.5f32, 0.25, -0.5, -0.25].repeat(256));
+    wait_for_analysis(&rx);
+
+    let mut mono = pipeline.input(&stream_config(1, 44100));
+    mono.push(&[0.25f32, -0.25].repeat(256));
+    let snapshot = pipeline.snapshot();
+    // Analysis of the stereo input may still be in flight.
+    while snapshot.load().channel_levels.len() != 1 {
+        wait_for_analysis(&rx);
+    }
+
+    assert_eq!(snapshot.load().level_u8(), (63, 63));
+}
@@ -0,0 +1,114 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use qmk_colormusic::{
+    analyzer::Analyzer,
+    file_source::FileSource,
+    pipeline::Pipeline,
+    playback::{Playback, PlaybackConfig, SampleSource},
+};
+
+// 16 bit PCM square waves with the given amplitude per channel.
+fn write_wav(name: &str, amplitudes: &[f32], sample_rate: u32, frames: usize) -> PathBuf {
+    let channels = amplitudes.len() as u16;
+    let data: Vec<u8> = (0..frames)
+        .flat_map(|frame| {
+            let sign = if frame % 2 == 0 { 1.0 } else { -1.0 };
+            amplitudes
+                .iter()
+                .flat_map(move |amplitude| ((amplitude * sign * 32767.0) as i16).to_le_bytes())
+        })
+        .collect();
+
+    let mut wav = Vec::new();
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&channels.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * channels as u32 * 2).to_le_bytes());
+    wav.extend_from_slice(&(channels * 2).to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(&data);
+
+    let path = std::env::temp_dir().join(format!("qmk-colormusic-{}-{}", std::process::id(), name));
+    fs::write(&path, wav).unwrap();
+    path
+}
+
+fn play_to_end(path: &Path, speed: f32) -> ((u8, u8), Duration) {
+    let (tx, _rx) = mpsc::channel();
+    let pipeline = Pipeline::spawn(Analyzer::default(), Analyzer::snapshot, tx);
+    let source = FileSource::open(path).unwrap();
+    let config = PlaybackConfig {
+        speed,
+        ..PlaybackConfig::default()
+    };
+
+    let started = Instant::now();
+    let mut playback = Playback::spawn(source.name(), Box::new(source), &config, &pipeline);
+    let result = loop {
+        if let Some(result) = playback.finished() {
+            break result;
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    let elapsed = started.elapsed();
+    result.unwrap();
+    // Give the worker a moment to analyze what is still queued.
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(pipeline.dropped_frames(), 0);
+    (pipeline.snapshot().load().level_u8(), elapsed)
+}
+
+#[test]
+fn decodes_wav_file() {
+    let path = write_wav("decode.wav", &[0.5, 0.25], 44100, 4410);
+    let mut source = FileSource::open(&path).unwrap();
+
+    assert_eq!(source.config().channels, 2);
+    assert_eq!(source.config().sample_rate.0, 44100);
+    assert_eq!(source.name(), path.file_name().unwrap().to_str().unwrap());
+
+    let mut samples = Vec::new();
+    while source.read(&mut samples).unwrap() {}
+    assert_eq!(samples.len(), 4410 * 2);
+    assert!((samples[0] - 0.5).abs() < 1e-3);
+    assert!((samples[3] + 0.25).abs() < 1e-3);
+
+    source.rewind().unwrap();
+    let mut again = Vec::new();
+    assert!(source.read(&mut again).unwrap());
+    assert_eq!(again[..], samples[..again.len()]);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn plays_file_as_fast_as_possible() {
+    // Ten seconds of audio, longer than the pipeline buffer.
+    let path = write_wav("fast.wav", &[0.5, 0.25], 48000, 480000);
+    let (level, elapsed) = play_to_end(&path, 0.0);
+
+    assert_eq!(level, (127, 63));
+    assert!(elapsed < Duration::from_secs(5));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn plays_file_in_real_time() {
+    let path = write_wav("realtime.wav", &[0.5], 48000, 9600);
+    let (level, elapsed) = play_to_end(&path, 1.0);
+
+    assert_eq!(level, (127, 127));
+    assert!(elapsed >= Duration::from_millis(190));
+    fs::remove_file(path).unwrap();
+}
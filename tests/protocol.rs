@@ -0,0 +1,168 @@
+use proptest::prelude::*;
+use qmk_colormusic::protocol::{
+    Command, CommandEncodeError, CommandParseError, Protocol, CUSTOM_DATA_CHUNK, MAX_BANDS,
+    PAGE_SIZE,
+};
+
+fn command() -> impl Strategy<Value = Command> {
+    let custom_data = (0..=CUSTOM_DATA_CHUNK, 1..=u8::MAX)
+        .prop_flat_map(|(length, count)| {
+            (
+                Just(length),
+                0..count,
+                Just(count),
+                prop::collection::vec(any::<u8>(), length),
+            )
+        })
+        .prop_map(|(length, sequence, count, payload)| {
+            let mut data = [0; CUSTOM_DATA_CHUNK];
+            data[..length].copy_from_slice(&payload);
+            Command::CustomData {
+                length: length as u8,
+                sequence,
+                count,
+                data,
+            }
+        });
+    let bands = prop::collection::vec(any::<u8>(), 0..=MAX_BANDS).prop_map(|bands| {
+        let mut values = [0; MAX_BANDS];
+        values[..bands.len()].copy_from_slice(&bands);
+        Command::Bands {
+            count: bands.len() as u8,
+            values,
+        }
+    });
+    prop_oneof![
+        any::<u8>().prop_map(|status| Command::Handshake { status }),
+        any::<(u8, u8)>().prop_map(|(left, right)| Command::RMS { left, right }),
+        custom_data,
+        any::<(u8, u8)>().prop_map(|(version, capabilities)| Command::Version {
+            version,
+            capabilities
+        }),
+        bands,
+        any::<(u8, u8)>().prop_map(|(strength, bpm)| Command::Beat { strength, bpm }),
+        Just(Command::Release),
+        any::<u8>().prop_map(|timeout| Command::Take { timeout }),
+        Just(Command::Heartbeat),
+        Just(Command::CycleMode),
+        Just(Command::Sensitivity),
+        Just(Command::Pause),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn every_command_survives_a_round_trip(command in command()) {
+        let protocol = Protocol::default();
+        let report = protocol.prepare_command(&command).unwrap();
+        prop_assert_eq!(protocol.to_command(&report[1..]), Ok(command));
+        prop_assert_eq!(Command::try_from(command.to_data().unwrap().as_slice()), Ok(command));
+    }
+
+    #[test]
+    fn arbitrary_reports_never_panic(data in prop::collection::vec(any::<u8>(), 0..64)) {
+        let _ = Protocol::default().to_command(&data);
+        let _ = Command::try_from(data.as_slice());
+    }
+
+    #[test]
+    fn encodes_only_what_decodes(length in any::<u8>(), count in any::<u8>()) {
+        let protocol = Protocol::default();
+        let custom_data = Command::CustomData {
+            length,
+            sequence: 0,
+            count: 1,
+            data: [0; CUSTOM_DATA_CHUNK],
+        };
+        let bands = Command::Bands { count, values: [0; MAX_BANDS] };
+        for command in [custom_data, bands] {
+            match protocol.prepare_command(&command) {
+                Ok(report) => prop_assert_eq!(protocol.to_command(&report[1..]), Ok(command)),
+                Err(_) => prop_assert!(length as usize > CUSTOM_DATA_CHUNK || count as usize > MAX_BANDS),
+            }
+        }
+    }
+}
+
+#[test]
+fn rejects_malformed_reports() {
+    let protocol = Protocol::default();
+    let mut report = protocol
+        .prepare_command(&Command::RMS { left: 1, right: 2 })
+        .unwrap();
+
+    assert_eq!(
+        protocol.to_command(b"kb"),
+        Err(CommandParseError::ReportLengthError(2))
+    );
+    assert_eq!(
+        protocol.to_command(&[0; PAGE_SIZE]),
+        Err(CommandParseError::ReportLengthError(PAGE_SIZE))
+    );
+    assert_eq!(
+        protocol.to_command(b"kbm"),
+        Err(CommandParseError::CommandByteError)
+    );
+    assert_eq!(
+        protocol.to_command(b"kbx\x02\x01\x02"),
+        Err(CommandParseError::CorruptedHeader)
+    );
+    assert_eq!(
+        protocol.to_command(b"kbm\x02\x01"),
+        Err(CommandParseError::RMSValueError(1))
+    );
+    assert_eq!(
+        protocol.to_command(b"kbm\xff"),
+        Err(CommandParseError::UndefinedCommand(0xff))
+    );
+    assert_eq!(
+        Command::try_from(&[0x03, 4, 1, 1][..]),
+        Err(CommandParseError::CustomDataSequenceError)
+    );
+
+    report[10] = 7;
+    assert_eq!(
+        protocol.to_command(&report[1..]),
+        Err(CommandParseError::ReservedByteError(6))
+    );
+}
+
+#[test]
+fn rejects_commands_it_cannot_encode() {
+    let mut data = [0; CUSTOM_DATA_CHUNK];
+    data[4] = 1;
+    let custom_data = |length, sequence, count| Command::CustomData {
+        length,
+        sequence,
+        count,
+        data,
+    };
+    let mut values = [0; MAX_BANDS];
+    values[3] = 9;
+
+    assert_eq!(
+        custom_data(25, 0, 1).to_data(),
+        Err(CommandEncodeError::CustomDataLengthError(25))
+    );
+    assert_eq!(
+        custom_data(5, 1, 1).to_data(),
+        Err(CommandEncodeError::CustomDataSequenceError {
+            sequence: 1,
+            count: 1
+        })
+    );
+    assert_eq!(
+        custom_data(4, 0, 1).to_data(),
+        Err(CommandEncodeError::CustomDataPaddingError)
+    );
+    assert_eq!(
+        Command::Bands { count: 28, values }.to_data(),
+        Err(CommandEncodeError::BandCountError(28))
+    );
+    assert_eq!(
+        Command::Bands { count: 3, values }.to_data(),
+        Err(CommandEncodeError::BandPaddingError)
+    );
+    assert!(Command::Bands { count: 4, values }.to_data().is_ok());
+}
@@ -0,0 +1,115 @@
+mod common;
+
+use std::{
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use qmk_colormusic::{
+    analyzer::SharedSnapshot,
+    connection::{ConnectionState, Supervisor},
+    protocol::{Command, ThreadCommand},
+};
+
+use common::MockKeyboard;
+
+fn wait_for(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while !condition() {
+        assert!(Instant::now() < deadline, "condition not reached in time");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn reconnects_after_unplug() {
+    let first = MockKeyboard::new();
+    let second = MockKeyboard::new();
+    let mut keyboards = vec![second.clone(), first.clone()];
+
+    let mut supervisor = Supervisor::new();
+    supervisor.rescan_interval = Duration::from_millis(10);
+    let state = supervisor.state();
+    let snapshot = SharedSnapshot::default();
+    let (tx, rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        let connect = move || Ok(keyboards.pop().map(|k| ("mock".to_owned(), k)));
+        supervisor.run(connect, snapshot, rx)
+    });
+
+    wait_for(|| matches!(*state.lock().unwrap(), ConnectionState::Connected { .. }));
+    first.unplug();
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    wait_for(|| second.is_handshaken());
+    wait_for(|| matches!(*state.lock().unwrap(), ConnectionState::Connected { .. }));
+
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    drop(tx);
+    handle.join().unwrap().unwrap();
+
+    // The closed channel hands the lighting back after the last levels.
+    assert!(second
+        .received()
+        .ends_with(&[Command::RMS { left: 0, right: 0 }, Command::Release]));
+}
+
+fn stop_session(keyboard: &MockKeyboard) {
+    let mut keyboards = vec![keyboard.clone()];
+
+    let supervisor = Supervisor::new();
+    let state = supervisor.state();
+    let snapshot = SharedSnapshot::default();
+    let (tx, rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        let connect = move || Ok(keyboards.pop().map(|k| ("mock".to_owned(), k)));
+        supervisor.run(connect, snapshot, rx)
+    });
+
+    wait_for(|| matches!(*state.lock().unwrap(), ConnectionState::Connected { .. }));
+    tx.send(ThreadCommand::Stop).unwrap();
+    // The sender stays alive, so only the stop command can end the thread.
+    wait_for(|| handle.is_finished());
+    handle.join().unwrap().unwrap();
+    drop(tx);
+}
+
+#[test]
+fn stop_releases_control() {
+    let keyboard = MockKeyboard::new();
+    stop_session(&keyboard);
+
+    // Handshake, then taking and releasing control.
+    assert_eq!(
+        keyboard.received()[3..],
+        [Command::Take { timeout: 20 }, Command::Release]
+    );
+}
+
+#[test]
+fn stop_rests_levels_on_legacy_firmware() {
+    let keyboard = MockKeyboard::legacy();
+    stop_session(&keyboard);
+
+    assert_eq!(
+        keyboard.received().last(),
+        Some(&Command::RMS { left: 0, right: 0 })
+    );
+}
+
+#[test]
+fn stop_ends_search() {
+    let mut supervisor = Supervisor::new();
+    supervisor.rescan_interval = Duration::from_millis(10);
+    let (tx, rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        let connect = || Ok(None::<(String, MockKeyboard)>);
+        supervisor.run(connect, SharedSnapshot::default(), rx)
+    });
+
+    tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    tx.send(ThreadCommand::Stop).unwrap();
+    wait_for(|| handle.is_finished());
+    handle.join().unwrap().unwrap();
+    drop(tx);
+}
@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use qmk_colormusic::{
+    analyzer::AnalysisConfig,
+    audio_capture::{Downmix, LevelMetric, LevelScale, RmsProcessor},
+};
+
+// Square waves with the given amplitude per channel, interleaved.
+fn interleaved(amplitudes: &[f32], frames: usize) -> Vec<f32> {
+    (0..frames)
+        .flat_map(|frame| {
+            let sign = if frame % 2 == 0 { 1.0 } else { -1.0 };
+            amplitudes.iter().map(move |amplitude| amplitude * sign)
+        })
+        .collect()
+}
+
+fn assert_close(actual: &[f32], expected: &[f32]) {
+    assert_eq!(actual.len(), expected.len());
+    for (a, e) in actual.iter().zip(expected) {
+        assert!((a - e).abs() < 1e-4, "{:?} != {:?}", actual, expected);
+    }
+}
+
+#[test]
+fn mono_drives_both_meters() {
+    let mut processor = RmsProcessor::new();
+    processor.process_samples(&interleaved(&[0.5], 256), 1, 48000);
+
+    assert_eq!(processor.get_rms_u8(), (127, 127));
+    assert_close(&processor.get_channel_rms(), &[0.5]);
+}
+
+#[test]
+fn stereo_keeps_channels_apart() {
+    let mut processor = RmsProcessor::new();
+    processor.process_samples(&interleaved(&[0.5, 0.25], 256), 2, 48000);
+
+    assert_eq!(processor.get_rms_u8(), (127, 63));
+    assert_close(&processor.get_channel_rms(), &[0.5, 0.25]);
+}
+
+#[test]
+fn surround_is_folded_without_lfe() {
+    // FL FR FC LFE BL BR
+    let data = interleaved(&[0.25, 0.125, 0.5, 1.0, 0.0, 0.0], 256);
+
+    let mut auto = RmsProcessor::new();
+    auto.process_samples(&data, 6, 48000);
+    assert_close(&auto.get_channel_rms(), &[0.25, 0.125, 0.5, 1.0, 0.0, 0.0]);
+    // Center is shared at -3 dB, LFE is left out.
+    let center = 0.5 * std::f32::consts::FRAC_1_SQRT_2;
+    let (left, right) = auto.get_rms_u8();
+    assert_eq!(left, ((0.25 + center) * 255.0) as u8);
+    assert_eq!(right, ((0.125 + center) * 255.0) as u8);
+
+    let mut front = RmsProcessor::with_downmix(Downmix::Front);
+    front.process_samples(&data, 6, 48000);
+    assert_eq!(front.get_rms_u8(), (63, 31));
+
+    let mut mapped = RmsProcessor::with_downmix(Downmix::Map { left: 2, right: 3 });
+    mapped.process_samples(&data, 6, 48000);
+    assert_eq!(mapped.get_rms_u8(), (127, 255));
+}
+
+#[test]
+fn reports_both_stereo_sides() {
+    let mut processor = RmsProcessor::new();
+    processor.process_samples(&interleaved(&[0.5, 0.25], 256), 2, 48000);
+
+    let (left, right) = processor.get_rms::<f32>();
+    assert!((left - 0.5).abs() < 1e-4);
+    assert!((right - 0.25).abs() < 1e-4);
+}
+
+#[test]
+fn reports_peak_and_dbfs() {
+    let mut processor = RmsProcessor::new().with_db_floor(-60.0);
+    // A single 0.5 spike in an otherwise quiet right channel.
+    let mut data = interleaved(&[0.1, 0.0], 100);
+    data[51] = 0.5;
+    processor.process_samples(&data, 2, 48000);
+
+    let (left, right) = processor.get_levels();
+    assert!((left.peak - 0.1).abs() < 1e-4);
+    assert!((right.peak - 0.5).abs() < 1e-4);
+    assert!((right.rms - 0.05).abs() < 1e-4);
+
+    let (left_db, right_db) = processor.get_dbfs(LevelMetric::Peak);
+    assert!((left_db + 20.0).abs() < 1e-3);
+    assert!((right_db + 6.0206).abs() < 1e-3);
+
+    processor.process_samples(&[0f32; 200], 2, 48000);
+    assert_eq!(processor.get_dbfs(LevelMetric::Rms), (-60.0, -60.0));
+}
+
+#[test]
+fn holds_peak_then_releases() {
+    let mut processor = RmsProcessor::new()
+        .with_metric(LevelMetric::PeakHold, LevelScale::Linear)
+        .with_peak_hold(Duration::from_millis(100));
+    processor.process_samples(&interleaved(&[0.8], 480), 1, 48000);
+    let quiet = interleaved(&[0.1], 480);
+
+    // 10 ms blocks, the peak is held for the first 100 ms.
+    for _ in 0..9 {
+        processor.process_samples(&quiet, 1, 48000);
+    }
+    assert!((processor.get_levels().0.peak_hold - 0.8).abs() < 1e-4);
+    assert_eq!(processor.get_level_u8().0, 204);
+
+    for _ in 0..100 {
+        processor.process_samples(&quiet, 1, 48000);
+    }
+    let levels = processor.get_levels().0;
+    assert!(levels.peak_hold < 0.8);
+    assert!(levels.peak_hold >= levels.peak);
+}
+
+#[test]
+fn maps_selected_metric_to_level() {
+    let data = interleaved(&[0.1, 1.0], 256);
+
+    let mut peak_db = RmsProcessor::new()
+        .with_metric(LevelMetric::Peak, LevelScale::Db)
+        .with_db_floor(-40.0);
+    peak_db.process_samples(&data, 2, 48000);
+    // -20 dBFS is half way to a -40 dB floor.
+    let (left, right) = peak_db.get_level();
+    assert!((left - 0.5).abs() < 1e-3);
+    assert!((right - 1.0).abs() < 1e-3);
+    assert_close(&peak_db.get_channel_level(), &[0.5, 1.0]);
+
+    // Above full scale still reads as a full meter.
+    peak_db.process_samples(&interleaved(&[1.5, 2.0], 256), 2, 48000);
+    assert_eq!(peak_db.get_level(), (1.0, 1.0));
+
+    let config: AnalysisConfig = toml::from_str(
+        r#"
+        level_metric = "peak-hold"
+        level_scale = "db"
+        level_db_floor = -48.0
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.level_metric, LevelMetric::PeakHold);
+    assert_eq!(config.level_scale, LevelScale::Db);
+}
+
+#[test]
+fn folds_layouts_without_center() {
+    // FL FR LFE
+    let mut two_one = RmsProcessor::new();
+    two_one.process_samples(&interleaved(&[0.25, 0.125, 1.0], 256), 3, 48000);
+    assert_eq!(two_one.get_rms_u8(), (63, 31));
+
+    // FL FR BL BR
+    let mut quad = RmsProcessor::new();
+    quad.process_samples(&interleaved(&[0.25, 0.0, 0.0, 0.5], 256), 4, 48000);
+    let back = 0.5 * std::f32::consts::FRAC_1_SQRT_2;
+    assert_eq!(quad.get_rms_u8(), (63, (back * 255.0) as u8));
+}
+
+#[test]
+fn rejects_mapping_missing_channels() {
+    let mapped = Downmix::Map { left: 0, right: 2 };
+
+    assert!(mapped.check(3).is_ok());
+    assert!(mapped.check(2).is_err());
+    assert!(Downmix::Auto.check(1).is_ok());
+}
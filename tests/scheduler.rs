@@ -0,0 +1,142 @@
+mod common;
+
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use qmk_colormusic::{
+    analyzer::{SharedSnapshot, Snapshot},
+    handshake::FirmwareInfo,
+    keyboard::{hid_thread, OutputConfig},
+    protocol::{Command, Protocol, ThreadCommand},
+    scheduler::{Scheduler, SharedSendStats},
+};
+
+use common::MockKeyboard;
+
+fn spawn_keyboard(
+    rate: u32,
+) -> (
+    MockKeyboard,
+    SharedSnapshot,
+    SharedSendStats,
+    mpsc::Sender<ThreadCommand>,
+    thread::JoinHandle<anyhow::Result<()>>,
+) {
+    let keyboard = MockKeyboard::new();
+    let snapshot = SharedSnapshot::default();
+    let stats = SharedSendStats::default();
+    let (tx, rx) = mpsc::channel();
+    let handle = {
+        let keyboard = keyboard.clone();
+        let snapshot = snapshot.clone();
+        let stats = stats.clone();
+        thread::spawn(move || {
+            let output = OutputConfig {
+                rate,
+                ..OutputConfig::default()
+            };
+            hid_thread(
+                &keyboard,
+                &Protocol::default(),
+                snapshot,
+                &rx,
+                &FirmwareInfo::legacy(),
+                &output,
+                &stats,
+            )
+        })
+    };
+    (keyboard, snapshot, stats, tx, handle)
+}
+
+fn set_level(snapshot: &SharedSnapshot, level: f32) {
+    snapshot.store(Arc::new(Snapshot {
+        level: (level, level),
+        ..Snapshot::default()
+    }));
+}
+
+#[test]
+fn coalesces_analysis_between_ticks() {
+    let (keyboard, snapshot, stats, tx, handle) = spawn_keyboard(1);
+    set_level(&snapshot, 1.0);
+    for _ in 0..5 {
+        tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    }
+    thread::sleep(Duration::from_millis(50));
+    set_level(&snapshot, 0.5);
+    for _ in 0..5 {
+        tx.send(ThreadCommand::ProcessorComplete).unwrap();
+    }
+    drop(tx);
+    handle.join().unwrap().unwrap();
+
+    // The first analysis goes out right away, the rest is flushed with the latest levels.
+    assert_eq!(
+        keyboard.received(),
+        [
+            Command::RMS {
+                left: 255,
+                right: 255
+            },
+            Command::RMS {
+                left: 127,
+                right: 127
+            }
+        ]
+    );
+    assert_eq!(stats.lock().unwrap().sent, 2);
+    assert_eq!(stats.lock().unwrap().dropped, 8);
+}
+
+#[test]
+fn limits_report_rate() {
+    let (keyboard, snapshot, stats, tx, handle) = spawn_keyboard(20);
+    let start = Instant::now();
+    let mut level = 0f32;
+    while start.elapsed() < Duration::from_millis(500) {
+        level = (level + 0.01) % 1.0;
+        set_level(&snapshot, level);
+        tx.send(ThreadCommand::ProcessorComplete).unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+    drop(tx);
+    handle.join().unwrap().unwrap();
+
+    let sent = keyboard.received().len();
+    assert!((8..=13).contains(&sent), "sent {} reports", sent);
+    assert_eq!(stats.lock().unwrap().sent as usize, sent);
+}
+
+#[test]
+fn skips_unchanged_levels() {
+    let (keyboard, snapshot, stats, tx, handle) = spawn_keyboard(0);
+    set_level(&snapshot, 0.5);
+    for _ in 0..3 {
+        tx.send(ThreadCommand::ProcessorComplete).unwrap();
+        thread::sleep(Duration::from_millis(5));
+    }
+    drop(tx);
+    handle.join().unwrap().unwrap();
+
+    assert_eq!(keyboard.received().len(), 1);
+    assert_eq!(stats.lock().unwrap().skipped, 2);
+}
+
+#[test]
+fn rate_drops_to_zero_without_commands() {
+    let stats = SharedSendStats::default();
+    let mut scheduler = Scheduler::new(0, stats.clone());
+    scheduler.take(&[Command::RMS { left: 1, right: 1 }]);
+    thread::sleep(Duration::from_millis(1010));
+    scheduler.take(&[Command::RMS { left: 2, right: 2 }]);
+    assert!(stats.lock().unwrap().rate > 0.0);
+
+    thread::sleep(Duration::from_millis(1010));
+    assert!(!scheduler.take(&[]));
+    assert_eq!(stats.lock().unwrap().rate, 0.0);
+    assert_eq!(stats.lock().unwrap().sent, 2);
+}
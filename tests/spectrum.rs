@@ -0,0 +1,40 @@
+use std::f32::consts::PI;
+
+use qmk_colormusic::spectrum::SpectrumProcessor;
+
+fn sine(frequency: f32, sample_rate: u32, frames: usize) -> Vec<f32> {
+    (0..frames)
+        .map(|i| (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin())
+        .collect()
+}
+
+#[test]
+fn full_scale_sine_peaks_near_zero_db() {
+    let mut processor = SpectrumProcessor::new(1024, 256, 16);
+    processor.process_samples(&sine(1500.0, 48000, 4096), 1, 48000);
+
+    let spectrum = processor.get_spectrum_db();
+    let peak = (0..spectrum.len())
+        .max_by(|a, b| spectrum[*a].total_cmp(&spectrum[*b]))
+        .unwrap();
+    assert!((processor.bin_frequency(peak) - 1500.0).abs() <= 47.0);
+    assert!(spectrum[peak] > -1.5 && spectrum[peak] <= 0.5);
+    assert!(spectrum[peak / 4] < -50.0);
+}
+
+#[test]
+fn log_bands_follow_sine_frequency() {
+    let mut low = SpectrumProcessor::new(2048, 512, 12);
+    let mut high = SpectrumProcessor::new(2048, 512, 12);
+    low.process_samples(&sine(100.0, 44100, 8192), 1, 44100);
+    high.process_samples(&sine(8000.0, 44100, 8192), 1, 44100);
+
+    let loudest = |bands: &[f32]| {
+        (0..bands.len())
+            .max_by(|a, b| bands[*a].total_cmp(&bands[*b]))
+            .unwrap()
+    };
+    assert!(loudest(low.get_bands_db()) < 4);
+    assert!(loudest(high.get_bands_db()) > 8);
+    assert!(low.get_bands_u8().iter().any(|band| *band > 240));
+}